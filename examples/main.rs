@@ -17,17 +17,21 @@ async fn index(session: Session) -> Result<&'static str> {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let session = ServerSession::signed(&[0; 32])
+        .secure(false)
+        .set_timeout(1);
+
+    let server_session = session.clone();
     HttpServer::new(move || {
         App::new()
-            .wrap(
-                ServerSession::signed(&[0; 32])
-                    .secure(false)
-                    .set_timeout(1)
-            )
+            .wrap(server_session.clone())
             .service(index)
     })
         // .workers(1)
         .bind("127.0.0.1:8080")?
         .run()
-        .await
+        .await?;
+
+    let _ = session.shutdown().await;
+    Ok(())
 }
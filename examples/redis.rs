@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use actix_web::{App, get, HttpServer, Result};
+
+use actix_server_session::{RedisSessionStore, ServerSession, Session};
+
+#[get("/")]
+async fn index(session: Session) -> Result<&'static str> {
+
+    if let Some(count) = session.get::<i32>("counter")? {
+        println!("SESSION value: {}", count);
+        session.set("counter", count + 1)?;
+    } else {
+        session.set("counter", 1)?;
+    }
+    Ok("Welcome!")
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let store = RedisSessionStore::new("redis://127.0.0.1/")
+        .expect("invalid redis url")
+        .prefix("example:");
+
+    let session = ServerSession::signed(&[0; 32])
+        .secure(false)
+        .set_timeout(1)
+        .store(Arc::new(store));
+
+    HttpServer::new(move || {
+        App::new()
+            .wrap(session.clone())
+            .service(index)
+    })
+        .bind("127.0.0.1:8080")?
+        .run()
+        .await
+}
@@ -0,0 +1,154 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use actix_web::cookie::Key;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::USER_AGENT;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// What the middleware does with a session used by another client than the
+/// one it was created for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BindingAction {
+    /// Drop the session, the request gets a new empty one.
+    Purge,
+    /// Keep the state but move it to a new id, bound to the new client.
+    ///
+    /// The new client takes the session over, with its principal, and the old
+    /// one loses it. CSRF tokens issued before are no longer valid.
+    Renew,
+    /// Keep the session as is, handlers decide with `Session::client_mismatch`.
+    Flag,
+}
+
+/// Client properties sessions are bound to.
+///
+/// A fingerprint of the properties is saved with the session when it is
+/// created and compared on every request. Sessions created before binding
+/// was enabled are bound to the first client using them.
+///
+/// The IP address is the address of the peer, behind a proxy it is the
+/// address of the proxy. TLS properties of the connection can't be bound,
+/// actix-web doesn't expose them to middleware.
+///
+/// Fingerprints are keyed with the cookie key, so a store dump doesn't reveal
+/// the bound properties.
+#[derive(Clone, Debug)]
+pub struct ClientBinding {
+    action: BindingAction,
+    user_agent: bool,
+    ip_prefix: Option<(u8, u8)>,
+}
+
+impl ClientBinding {
+    /// Construct new `ClientBinding` binding nothing yet, taking `action` on mismatch.
+    pub fn new(action: BindingAction) -> Self {
+        ClientBinding {
+            action,
+            user_agent: false,
+            ip_prefix: None,
+        }
+    }
+
+    /// Binds sessions to the `User-Agent` header.
+    pub fn user_agent(mut self) -> Self {
+        self.user_agent = true;
+        self
+    }
+
+    /// Binds sessions to the IP address of the client.
+    pub fn ip(self) -> Self {
+        self.ip_prefix(32, 128)
+    }
+
+    /// Binds sessions to the network of the client, the first `v4` bits of
+    /// IPv4 addresses and `v6` bits of IPv6 addresses, so clients moving within
+    /// their network keep their session.
+    ///
+    /// Panics if `v4` is greater than 32 or `v6` greater than 128.
+    pub fn ip_prefix(mut self, v4: u8, v6: u8) -> Self {
+        assert!(v4 <= 32 && v6 <= 128, "IP prefix longer than the address");
+        self.ip_prefix = Some((v4, v6));
+        self
+    }
+
+    pub fn action(&self) -> BindingAction {
+        self.action
+    }
+
+    /// Fingerprint of the bound properties of the client sending `req`, keyed with `key`.
+    pub(crate) fn fingerprint(&self, key: &Key, req: &ServiceRequest) -> String {
+        let mut hasher = Hmac::<Sha256>::new_varkey(key.signing()).expect("HMAC accepts any key length");
+        hasher.update(b"client fingerprint\0");
+        if self.user_agent {
+            let user_agent = req.headers().get(USER_AGENT).map(|value| value.as_bytes());
+            hasher.update(user_agent.unwrap_or_default());
+        }
+        hasher.update(&[0]);
+        if let (Some((v4, v6)), Some(addr)) = (self.ip_prefix, req.peer_addr()) {
+            let network = match addr.ip() {
+                IpAddr::V4(ip) => IpAddr::from(Ipv4Addr::from(u32::from(ip) & mask(v4.into(), 32) as u32)),
+                IpAddr::V6(ip) => IpAddr::from(Ipv6Addr::from(u128::from(ip) & mask(v6.into(), 128))),
+            };
+            hasher.update(network.to_string().as_bytes());
+        }
+        base64::encode_config(hasher.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// Mask keeping the first `prefix` of `width` bits.
+fn mask(prefix: u32, width: u32) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => (u128::MAX >> (128 - width)) & !((1u128 << (width - prefix)) - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn request(user_agent: &str, ip: &str) -> ServiceRequest {
+        TestRequest::default()
+            .header(USER_AGENT, user_agent)
+            .peer_addr((ip.parse::<IpAddr>().unwrap(), 1234).into())
+            .to_srv_request()
+    }
+
+    #[test]
+    fn fingerprint_is_keyed() {
+        let binding = ClientBinding::new(BindingAction::Purge).user_agent();
+        let req = request("browser", "10.0.0.1");
+        let key = Key::derive_from(&[0; 32]);
+        assert_eq!(binding.fingerprint(&key, &req), binding.fingerprint(&key, &req));
+        assert_ne!(binding.fingerprint(&key, &req), binding.fingerprint(&Key::derive_from(&[1; 32]), &req));
+    }
+
+    #[test]
+    fn fingerprint_covers_bound_properties_only() {
+        let key = Key::derive_from(&[0; 32]);
+        let binding = ClientBinding::new(BindingAction::Purge).user_agent().ip_prefix(24, 64);
+        let fingerprint = |user_agent, ip| binding.fingerprint(&key, &request(user_agent, ip));
+        assert_eq!(fingerprint("browser", "10.0.0.1"), fingerprint("browser", "10.0.0.2"));
+        assert_ne!(fingerprint("browser", "10.0.0.1"), fingerprint("browser", "10.0.1.1"));
+        assert_ne!(fingerprint("browser", "10.0.0.1"), fingerprint("curl", "10.0.0.1"));
+        assert_eq!(fingerprint("browser", "2001:db8::1"), fingerprint("browser", "2001:db8::ffff:1"));
+        assert_ne!(fingerprint("browser", "2001:db8::1"), fingerprint("browser", "2001:db9::1"));
+
+        let binding = ClientBinding::new(BindingAction::Purge).ip();
+        let fingerprint = |user_agent, ip| binding.fingerprint(&key, &request(user_agent, ip));
+        assert_eq!(fingerprint("browser", "10.0.0.1"), fingerprint("curl", "10.0.0.1"));
+        assert_ne!(fingerprint("browser", "10.0.0.1"), fingerprint("browser", "10.0.0.2"));
+    }
+
+    #[test]
+    fn masks_keep_the_prefix() {
+        assert_eq!(mask(0, 32), 0);
+        assert_eq!(mask(24, 32), 0xffff_ff00);
+        assert_eq!(mask(32, 32), 0xffff_ffff);
+        assert_eq!(mask(64, 128), u128::MAX << 64);
+        assert_eq!(mask(128, 128), u128::MAX);
+    }
+}
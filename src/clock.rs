@@ -0,0 +1,76 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+#[cfg(any(test, feature = "redis-session", feature = "sqlite-session"))]
+use std::time::UNIX_EPOCH;
+
+/// Source of the current time for session expiry and cookies.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Milliseconds since the unix epoch, the unit stores keep times in.
+#[cfg(any(feature = "redis-session", feature = "sqlite-session"))]
+pub(crate) fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// `Clock` reading the system time. This is the default clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// `Clock` that only moves when told to, for tests.
+///
+/// Clones share the same time, so a test can keep one to advance the clock
+/// given to a `ServerSession` or a store.
+#[derive(Clone, Debug)]
+pub struct MockClock(Arc<Mutex<SystemTime>>);
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new(SystemTime::now())
+    }
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        MockClock(Arc::new(Mutex::new(now)))
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_clones_share_the_time() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let shared = clock.clone();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(shared.now(), UNIX_EPOCH + Duration::from_secs(60));
+        shared.set(UNIX_EPOCH);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,307 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, ResponseError};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::BytesMut;
+use derive_more::Display;
+use futures_util::future::{FutureExt, LocalBoxFuture, ok, Ready};
+use futures_util::stream::{self, StreamExt};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::session::UserSession;
+
+const SECRET_LEN: usize = 32;
+
+/// Errors returned by `Csrf` for requests failing the CSRF check
+#[derive(Debug, Display)]
+pub enum CsrfError {
+    /// The request carries no CSRF token.
+    #[display(fmt = "CSRF token missing")]
+    Missing,
+    /// The CSRF token doesn't belong to the session of the request.
+    #[display(fmt = "CSRF token mismatch")]
+    Mismatch,
+}
+
+impl ResponseError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+}
+
+/// CSRF token of the session of the request, to put in forms or headers.
+///
+/// Tokens change on every request but all of them stay valid until the
+/// session is renewed.
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = Error;
+    type Future = Ready<Result<CsrfToken, Error>>;
+    type Config = ();
+
+    #[inline]
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ok(CsrfToken(req.get_session().csrf_token()))
+    }
+}
+
+/// Returns a new random CSRF secret.
+pub(crate) fn new_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    base64::encode_config(secret, base64::URL_SAFE_NO_PAD)
+}
+
+/// Returns a token for `secret`, masked with random bytes so it never
+/// appears twice in responses.
+pub(crate) fn mask(secret: &str) -> String {
+    let secret = base64::decode_config(secret, base64::URL_SAFE_NO_PAD).unwrap_or_default();
+    let mut token = vec![0u8; secret.len()];
+    OsRng.fill_bytes(&mut token);
+    let masked: Vec<u8> = token.iter().zip(&secret).map(|(m, s)| m ^ s).collect();
+    token.extend(masked);
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// Checks that `token` was masked from `secret`, in constant time.
+fn verify(secret: &str, token: &str) -> bool {
+    let secret = match base64::decode_config(secret, base64::URL_SAFE_NO_PAD) {
+        Ok(secret) => secret,
+        Err(_) => return false,
+    };
+    let token = match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+        Ok(token) if token.len() == 2 * secret.len() => token,
+        _ => return false,
+    };
+    let (mask, masked) = token.split_at(secret.len());
+    let diff = mask.iter().zip(masked).zip(&secret)
+        .fold(0, |diff, ((m, t), s)| diff | (m ^ t ^ s));
+    diff == 0
+}
+
+struct CsrfInner {
+    header: String,
+    field: String,
+    limit: usize,
+}
+
+/// CSRF protection middleware.
+///
+/// Requests with an unsafe method must carry a token of `CsrfToken` in the
+/// header or, for url encoded forms, in the form field, or they get a
+/// `403 Forbidden`. It must be wrapped inside `ServerSession`:
+///
+/// ```rust,ignore
+/// App::new().wrap(Csrf::new()).wrap(ServerSession::signed(&[0; 32]))
+/// ```
+pub struct Csrf(Arc<CsrfInner>);
+
+impl Default for Csrf {
+    fn default() -> Self {
+        Csrf::new()
+    }
+}
+
+impl Csrf {
+    pub fn new() -> Csrf {
+        Csrf(Arc::new(CsrfInner {
+            header: "X-CSRF-Token".to_owned(),
+            field: "csrf_token".to_owned(),
+            limit: 16_384,
+        }))
+    }
+
+    /// Sets the header holding the token. Default is `X-CSRF-Token`.
+    pub fn header<S: Into<String>>(mut self, value: S) -> Csrf {
+        Arc::get_mut(&mut self.0).unwrap().header = value.into();
+        self
+    }
+
+    /// Sets the form field holding the token. Default is `csrf_token`.
+    pub fn field<S: Into<String>>(mut self, value: S) -> Csrf {
+        Arc::get_mut(&mut self.0).unwrap().field = value.into();
+        self
+    }
+
+    /// Sets the maximum size of form bodies read to find the token. Default is 16kB.
+    pub fn limit(mut self, value: usize) -> Csrf {
+        Arc::get_mut(&mut self.0).unwrap().limit = value;
+        self
+    }
+}
+
+impl<S, B: 'static> Transform<S> for Csrf
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            inner: self.0.clone(),
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    inner: Arc<CsrfInner>,
+}
+
+impl<S, B: 'static> Service for CsrfMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+        let inner = self.inner.clone();
+
+        async move {
+            let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE);
+            if !safe {
+                let token = match req.headers().get(inner.header.as_str()) {
+                    Some(value) => value.to_str().ok().map(ToOwned::to_owned),
+                    None => form_token(&mut req, &inner).await?,
+                };
+                let token = token.ok_or(CsrfError::Missing)?;
+                match req.get_session().csrf_secret() {
+                    Some(secret) if verify(&secret, &token) => {}
+                    _ => return Err(CsrfError::Mismatch.into()),
+                }
+            }
+            let fut = srv.borrow_mut().call(req);
+            fut.await
+        }.boxed_local()
+    }
+}
+
+/// Reads the token from the body of url encoded forms, leaving the body in
+/// place for the handler.
+async fn form_token(req: &mut ServiceRequest, inner: &CsrfInner) -> Result<Option<String>, Error> {
+    if !req.content_type().eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return Ok(None);
+    }
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > inner.limit {
+            return Err(PayloadError::Overflow.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+    req.set_payload(Payload::Stream(Box::pin(stream::once(ok(body)))));
+    Ok(fields.into_iter().find(|(name, _)| *name == inner.field).map(|(_, value)| value))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, test, web};
+    use actix_web::cookie::Cookie;
+    use actix_web::http::header::CONTENT_TYPE;
+
+    use crate::server_session::ServerSession;
+    use crate::server_session::tests::{renew, send_to};
+
+    use super::*;
+
+    #[test]
+    fn verify_accepts_masked_secret() {
+        let secret = new_secret();
+        let token = mask(&secret);
+        assert_ne!(token, mask(&secret));
+        assert!(verify(&secret, &token));
+    }
+
+    #[test]
+    fn verify_rejects_other_tokens() {
+        let secret = new_secret();
+        assert!(!verify(&secret, &mask(&new_secret())));
+        assert!(!verify(&secret, &secret));
+        assert!(!verify(&secret, "not base64!"));
+        assert!(!verify(&secret, &mask(&secret)[..40]));
+        assert!(!verify(&secret, ""));
+    }
+
+    async fn token(token: CsrfToken) -> HttpResponse {
+        HttpResponse::Ok().body(token.to_string())
+    }
+
+    async fn echo(form: web::Form<Vec<(String, String)>>) -> HttpResponse {
+        let name = form.0.into_iter().find(|(field, _)| field == "name").map(|(_, name)| name);
+        HttpResponse::Ok().body(name.unwrap_or_default())
+    }
+
+    async fn post(app: &mut impl Service<Request=actix_http::Request, Response=ServiceResponse, Error=Error>,
+                  req: test::TestRequest, cookie: &Cookie<'static>) -> Result<String, StatusCode> {
+        match app.call(req.method(Method::POST).cookie(cookie.clone()).to_request()).await {
+            Ok(res) => Ok(String::from_utf8(test::read_body(res).await.to_vec()).unwrap()),
+            Err(err) => Err(err.as_response_error().status_code()),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn checks_unsafe_requests() {
+        let mut app = test::init_service(App::new()
+            .wrap(Csrf::new())
+            .wrap(ServerSession::signed(&[0; 32]))
+            .route("/", web::get().to(token))
+            .route("/", web::post().to(echo))
+            .route("/renew", web::get().to(renew))).await;
+        let (cookie, token) = send_to(&mut app, "/", None).await;
+        let cookie = cookie.unwrap();
+
+        let form = |token: &str| test::TestRequest::default()
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .set_payload(format!("name=joe&csrf_token={}", token));
+        assert_eq!(post(&mut app, test::TestRequest::default(), &cookie).await, Err(StatusCode::FORBIDDEN));
+        assert_eq!(post(&mut app, form(&mask(&new_secret())), &cookie).await, Err(StatusCode::FORBIDDEN));
+        assert_eq!(post(&mut app, form(&token), &cookie).await.as_deref(), Ok("joe"));
+        let header = test::TestRequest::default().header("X-CSRF-Token", token.as_str());
+        assert!(post(&mut app, header, &cookie).await.is_ok());
+
+        // renewing the session invalidates its tokens
+        let (renewed, _) = send_to(&mut app, "/renew", Some(&cookie)).await;
+        assert_eq!(post(&mut app, form(&token), &renewed.unwrap()).await, Err(StatusCode::FORBIDDEN));
+    }
+}
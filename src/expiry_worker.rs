@@ -0,0 +1,141 @@
+use std::sync::{Mutex, Weak};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use actix_web::{Error, web};
+use futures_util::future::{FutureExt, LocalBoxFuture, ok};
+
+/// Thread removing expired sessions of a store periodically.
+///
+/// The thread stops when the worker is stopped or dropped, or when the sweep
+/// function reports that the store is gone.
+pub(crate) struct ExpiryWorker {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ExpiryWorker {
+    /// Spawns a thread calling `sweep` every `interval` until it returns `false`.
+    pub(crate) fn spawn<F>(interval: Duration, mut sweep: F) -> ExpiryWorker
+        where
+            F: FnMut() -> bool + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("session-expiry".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if !sweep() {
+                        break;
+                    }
+                }
+            })
+            .expect("failed to spawn session expiry thread");
+        ExpiryWorker {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Starts the worker of a store in `slot` unless it runs already, calling
+    /// `sweep` on the store every `interval` until the store is dropped.
+    pub(crate) fn start<T, F>(slot: &Mutex<Option<ExpiryWorker>>, interval: Duration, store: Weak<T>, sweep: F)
+        where
+            T: Send + Sync + 'static,
+            F: Fn(&T) + Send + 'static,
+    {
+        let mut worker = slot.lock().unwrap();
+        if worker.is_some() {
+            return;
+        }
+        *worker = Some(ExpiryWorker::spawn(interval, move || {
+            match store.upgrade() {
+                Some(store) => {
+                    sweep(&store);
+                    true
+                }
+                None => false,
+            }
+        }));
+    }
+
+    /// Stops the worker in `slot`, if any. The returned future resolves once
+    /// the sweep in progress is over, waiting for it off the actix worker.
+    pub(crate) fn shutdown(slot: &Mutex<Option<ExpiryWorker>>) -> LocalBoxFuture<'static, Result<(), Error>> {
+        let mut worker = match slot.lock().unwrap().take() {
+            Some(worker) => worker,
+            None => return ok(()).boxed_local(),
+        };
+        async move {
+            web::block(move || {
+                worker.stop();
+                Ok::<_, ()>(())
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    /// Stops the thread and waits for the sweep in progress, if any.
+    pub(crate) fn stop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            // the store may be dropped by its own worker, which can't join itself
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl Drop for ExpiryWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Waits until `sweeps` reaches `count`, failing after a second.
+    fn wait_for(sweeps: &AtomicUsize, count: usize) {
+        let start = Instant::now();
+        while sweeps.load(Ordering::SeqCst) < count {
+            assert!(start.elapsed() < Duration::from_secs(1), "worker didn't sweep");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn sweeps_until_stopped() {
+        let sweeps = Arc::new(AtomicUsize::new(0));
+        let counter = sweeps.clone();
+        let mut worker = ExpiryWorker::spawn(Duration::from_millis(1), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            true
+        });
+        wait_for(&sweeps, 3);
+        worker.stop();
+        let stopped = sweeps.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(sweeps.load(Ordering::SeqCst), stopped);
+    }
+
+    #[test]
+    fn exits_once_the_store_is_gone() {
+        let sweeps = Arc::new(AtomicUsize::new(0));
+        let counter = sweeps.clone();
+        let worker = ExpiryWorker::spawn(Duration::from_millis(1), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            false
+        });
+        wait_for(&sweeps, 1);
+        drop(worker);
+        assert_eq!(sweeps.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+
+use actix_web::{Error, web};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use rand::Rng;
+
+use crate::clock::{Clock, SystemClock};
+use crate::expiry_worker::ExpiryWorker;
+use crate::server_session_state::State;
+use crate::session_hooks::{call_hooks, SessionHook};
+use crate::session_store::{LifetimeCap, SessionStore, SessionStoreError};
+
+/// Number of locks the writes of sessions are spread over.
+const LOCKS: usize = 64;
+
+struct FileSessionStoreInner {
+    dir: PathBuf,
+    /// Serialize the writes of a session, picked by hashing its id.
+    locks: Vec<Mutex<()>>,
+    hasher: RandomState,
+    sweep_interval: Duration,
+    expire_hooks: RwLock<Vec<SessionHook>>,
+    max_lifetime: LifetimeCap,
+    clock: Arc<dyn Clock>,
+    worker: Mutex<Option<ExpiryWorker>>,
+}
+
+impl FileSessionStoreInner {
+    /// Path of the file holding session `id`.
+    ///
+    /// Ids come from client cookies, so anything that isn't a plain
+    /// file name is refused.
+    fn path(&self, id: &str) -> Result<PathBuf, SessionStoreError> {
+        let valid = !id.is_empty()
+            && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if valid {
+            Ok(self.dir.join(id))
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid session id").into())
+        }
+    }
+
+    /// Locks the writes of session `id`, so a state read and rewritten or
+    /// removed isn't changed in between.
+    fn lock(&self, id: &str) -> MutexGuard<'_, ()> {
+        let index = self.hasher.hash_one(id) as usize % self.locks.len();
+        self.locks[index].lock().unwrap()
+    }
+
+    fn read(&self, id: &str) -> Result<Option<State>, SessionStoreError> {
+        let path = match self.path(id) {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        read_state(&path)
+    }
+
+    /// Writes `state` to a temporary file, then renames it over the session file
+    /// so readers never see a partially written state.
+    fn write(&self, id: &str, bytes: &[u8]) -> Result<(), SessionStoreError> {
+        let path = self.path(id)?;
+        let suffix: u64 = rand::thread_rng().gen();
+        let tmp = self.dir.join(format!(".{}.{:x}.tmp", id, suffix));
+        fs::write(&tmp, bytes)?;
+        if let Err(e) = fs::rename(&tmp, &path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), SessionStoreError> {
+        let path = match self.path(id) {
+            Ok(path) => path,
+            Err(_) => return Ok(()),
+        };
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn ids(&self) -> Result<Vec<String>, SessionStoreError> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            // skip files being written
+            match entry?.file_name().into_string() {
+                Ok(name) if self.path(&name).is_ok() => ids.push(name),
+                _ => {}
+            }
+        }
+        Ok(ids)
+    }
+
+    fn sweep(&self) -> Result<usize, SessionStoreError> {
+        let now = self.clock.now();
+        let max_lifetime = self.max_lifetime.get();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            // skip files being written
+            let id = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if !name.starts_with('.') => name.to_owned(),
+                _ => continue,
+            };
+            let expired = |path: &Path| match read_state(path) {
+                Ok(Some(mut state)) => {
+                    state.cap_lifetime(max_lifetime);
+                    Some(state).filter(|state| state.is_expired(now))
+                }
+                _ => None,
+            };
+            if expired(&path).is_none() {
+                continue;
+            }
+            // the session may have been saved since, check it again under its lock
+            let _lock = self.lock(&id);
+            if let Some(state) = expired(&path) {
+                if fs::remove_file(&path).is_ok() {
+                    call_hooks(&self.expire_hooks.read().unwrap(), &id, &state);
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn read_state(path: &Path) -> Result<Option<State>, SessionStoreError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(State::from_bytes(&bytes)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Session store keeping every state in its own file.
+///
+/// Files are named after the session id and replaced atomically on every
+/// write, so sessions survive a restart of the process. Expired files are
+/// removed by `sweep`, which `start` runs periodically.
+///
+/// Writes of a session are serialized within the store, so a directory must
+/// only be used by one store at a time.
+#[derive(Clone)]
+pub struct FileSessionStore(Arc<FileSessionStoreInner>);
+
+impl FileSessionStore {
+    /// Construct new `FileSessionStore` writing into `dir`, creating it if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<FileSessionStore, SessionStoreError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileSessionStore(Arc::new(FileSessionStoreInner {
+            dir,
+            locks: (0..LOCKS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+            sweep_interval: Duration::from_secs(60),
+            expire_hooks: RwLock::new(Vec::new()),
+            max_lifetime: LifetimeCap::default(),
+            clock: Arc::new(SystemClock),
+            worker: Mutex::new(None),
+        })))
+    }
+
+    /// Sets how often `start` sweeps expired sessions. Default is one minute.
+    pub fn sweep_interval(mut self, value: Duration) -> FileSessionStore {
+        Arc::get_mut(&mut self.0).unwrap().sweep_interval = value;
+        self
+    }
+
+    /// Sets the clock deciding when sessions expire. Default is `SystemClock`.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> FileSessionStore {
+        Arc::get_mut(&mut self.0).unwrap().clock = Arc::new(clock);
+        self
+    }
+
+    /// Removes the files of expired sessions, returning how many were removed.
+    pub fn sweep(&self) -> Result<usize, SessionStoreError> {
+        self.0.sweep()
+    }
+
+    /// Starts the thread sweeping expired sessions periodically.
+    ///
+    /// It runs until `shutdown` is called or the store is dropped.
+    pub fn start(&self) {
+        ExpiryWorker::start(&self.0.worker, self.0.sweep_interval, Arc::downgrade(&self.0), |inner| {
+            let _ = inner.sweep();
+        });
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            Ok(web::block(move || inner.read(&id)).await?)
+        }.boxed_local()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            let bytes = inner.max_lifetime.apply(state).to_bytes()?;
+            Ok(web::block(move || {
+                let _lock = inner.lock(&id);
+                inner.write(&id, &bytes)
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn touch<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            web::block(move || {
+                let _lock = inner.lock(&id);
+                if let Some(mut state) = inner.read(&id)? {
+                    state.update_last_use_time(inner.clock.now());
+                    inner.write(&id, &inner.max_lifetime.apply(&state).to_bytes()?)?;
+                }
+                Ok::<_, SessionStoreError>(())
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            Ok(web::block(move || {
+                let _lock = inner.lock(&id);
+                inner.remove(&id)
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn ids(&self) -> LocalBoxFuture<'_, Result<Vec<String>, Error>> {
+        let inner = self.0.clone();
+        async move {
+            Ok(web::block(move || inner.ids()).await?)
+        }.boxed_local()
+    }
+
+    fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            Ok(web::block(move || {
+                Ok::<_, SessionStoreError>(inner.path(&id).map(|path| path.is_file()).unwrap_or(false))
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn on_expire(&self, hook: SessionHook) {
+        self.0.expire_hooks.write().unwrap().push(hook);
+    }
+
+    fn set_max_lifetime(&self, max_lifetime: Option<Duration>) {
+        self.0.max_lifetime.set(max_lifetime);
+    }
+
+    fn shutdown(&self) -> LocalBoxFuture<'_, Result<(), Error>> {
+        ExpiryWorker::shutdown(&self.0.worker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::clock::MockClock;
+    use crate::server_session_state::tests::{MINUTE, state_at};
+
+    use super::*;
+
+    /// Store in a new directory, removed when the returned guard is dropped.
+    fn store(clock: &MockClock) -> (FileSessionStore, TempDir) {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "file-session-store-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst),
+        ));
+        let store = FileSessionStore::new(&dir).unwrap().clock(clock.clone());
+        (store, TempDir(dir))
+    }
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn max_lifetime_caps_saved_files() {
+        let clock = MockClock::default();
+        let (store, _dir) = store(&clock);
+        store.0.write("old", &state_at(&clock, 60 * MINUTE).to_bytes().unwrap()).unwrap();
+        store.set_max_lifetime(Some(20 * MINUTE));
+        clock.advance(10 * MINUTE);
+        assert_eq!(store.sweep().unwrap(), 0);
+        clock.advance(11 * MINUTE);
+        assert_eq!(store.sweep().unwrap(), 1);
+        assert!(!store.exists("old").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn saves_touches_and_deletes() {
+        // states keep times in milliseconds
+        let clock = MockClock::new(std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let (store, _dir) = store(&clock);
+        store.save("a", &state_at(&clock, MINUTE)).await.unwrap();
+        clock.advance(Duration::from_secs(30));
+        store.touch("a").await.unwrap();
+        let state = store.load("a").await.unwrap().unwrap();
+        assert_eq!(state.last_use_time(), clock.now());
+        assert_eq!(store.ids().await.unwrap(), vec!["a".to_owned()]);
+        store.delete("a").await.unwrap();
+        assert!(store.load("a").await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn refuses_ids_outside_the_directory() {
+        let clock = MockClock::default();
+        let (store, _dir) = store(&clock);
+        assert!(store.save("../escape", &state_at(&clock, MINUTE)).await.is_err());
+        assert!(store.load("../escape").await.unwrap().is_none());
+        assert!(!store.exists("").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn sweep_removes_expired_sessions() {
+        let clock = MockClock::default();
+        let (store, _dir) = store(&clock);
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let hook = expired.clone();
+        store.on_expire(Arc::new(move |id: &str, _: &State| hook.lock().unwrap().push(id.to_owned())));
+        store.save("short", &state_at(&clock, MINUTE)).await.unwrap();
+        store.save("long", &state_at(&clock, 10 * MINUTE)).await.unwrap();
+        clock.advance(2 * MINUTE);
+        assert_eq!(store.sweep().unwrap(), 1);
+        assert_eq!(*expired.lock().unwrap(), vec!["short".to_owned()]);
+        assert!(store.exists("long").await.unwrap());
+    }
+
+    #[test]
+    fn sweep_keeps_a_session_saved_concurrently() {
+        let clock = MockClock::default();
+        let (store, _dir) = store(&clock);
+        store.0.write("a", &state_at(&clock, MINUTE).to_bytes().unwrap()).unwrap();
+        clock.advance(2 * MINUTE);
+        let lock = store.0.lock("a");
+        let sweeping = store.clone();
+        let sweep = std::thread::spawn(move || sweeping.sweep().unwrap());
+        // let the sweep find the expired state and wait on the lock
+        std::thread::sleep(Duration::from_millis(100));
+        store.0.write("a", &state_at(&clock, MINUTE).to_bytes().unwrap()).unwrap();
+        drop(lock);
+        assert_eq!(sweep.join().unwrap(), 0);
+        assert!(store.0.read("a").unwrap().is_some());
+    }
+}
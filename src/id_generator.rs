@@ -0,0 +1,62 @@
+use actix_web::ResponseError;
+use derive_more::Display;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+/// Errors that can occur while generating a session id
+#[derive(Debug, Display)]
+pub enum IdGeneratorError {
+    /// Every generated id was already used by a saved session.
+    #[display(fmt = "No unused session id after {} attempts", _0)]
+    Exhausted(usize),
+}
+
+impl ResponseError for IdGeneratorError {}
+
+/// Source of new session ids.
+///
+/// Ids are sent in the session cookie and used as store keys, so they should
+/// only contain `A-Z`, `a-z`, `0-9`, `-` and `_`.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+impl<F> IdGenerator for F
+    where
+        F: Fn() -> String + Send + Sync,
+{
+    fn generate(&self) -> String {
+        self()
+    }
+}
+
+/// `IdGenerator` encoding 256 bits from the OS random generator as base64url.
+/// This is the default generator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn generate(&self) -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_ids_are_unique_url_safe_tokens() {
+        let ids: Vec<String> = (0..64).map(|_| RandomIdGenerator.generate()).collect();
+        for id in &ids {
+            assert_eq!(id.len(), 43);
+            assert!(id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        }
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+    }
+}
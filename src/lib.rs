@@ -1,8 +1,42 @@
+pub use client_binding::{BindingAction, ClientBinding};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use csrf::{Csrf, CsrfError, CsrfToken};
+pub use id_generator::{IdGenerator, IdGeneratorError, RandomIdGenerator};
 pub use server_session::ServerSession;
+pub use server_session_inner::{CookiePrefix, CookiePrefixError};
+pub use rate_limit::{RateLimitAction, RateLimitError};
+pub use server_session_state::{EvictionPolicy, ServerSessionState, State};
 pub use session::Session;
+pub use session_codec::{SessionCodec, SessionCodecError};
+pub use session_hooks::SessionHook;
+pub use session_limit::{SessionLimitError, SessionLimitPolicy};
+pub use session_manager::SessionManager;
+pub use session_store::{SessionStore, SessionStoreError};
+#[cfg(feature = "file-session")]
+pub use file_session_store::FileSessionStore;
+#[cfg(feature = "redis-session")]
+pub use redis_session_store::RedisSessionStore;
+#[cfg(feature = "sqlite-session")]
+pub use sqlite_session_store::SqliteSessionStore;
 
+mod client_binding;
+mod clock;
+mod csrf;
+mod expiry_worker;
+mod id_generator;
+mod rate_limit;
 mod server_session;
 mod server_session_inner;
 mod server_session_state;
 mod session;
-
+mod session_codec;
+mod session_hooks;
+mod session_limit;
+mod session_manager;
+mod session_store;
+#[cfg(feature = "file-session")]
+mod file_session_store;
+#[cfg(feature = "redis-session")]
+mod redis_session_store;
+#[cfg(feature = "sqlite-session")]
+mod sqlite_session_store;
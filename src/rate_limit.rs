@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use derive_more::Display;
+
+/// What the middleware does with a request that would create a session
+/// beyond the creation rate limit of its IP address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Answer with `RateLimitError` without calling the handler.
+    Reject,
+    /// Call the handler with a session that is neither saved nor sent.
+    ServeWithoutSession,
+}
+
+/// Error returned for requests rejected by the session creation rate limit
+#[derive(Debug, Display)]
+#[display(fmt = "Too many new sessions from this address")]
+pub struct RateLimitError;
+
+impl ResponseError for RateLimitError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+struct Counters {
+    /// Start of the current window and sessions created in it, per address.
+    by_ip: HashMap<IpAddr, (SystemTime, u32)>,
+    /// Size at which outdated windows are dropped.
+    prune_at: usize,
+}
+
+/// Fixed window counter of the sessions created by each IP address.
+pub(crate) struct CreationLimiter {
+    max: u32,
+    window: Duration,
+    pub(crate) action: RateLimitAction,
+    counters: Mutex<Counters>,
+}
+
+impl CreationLimiter {
+    pub(crate) fn new(max: u32, window: Duration, action: RateLimitAction) -> Self {
+        CreationLimiter {
+            max,
+            window,
+            action,
+            counters: Mutex::new(Counters {
+                by_ip: HashMap::new(),
+                prune_at: 1024,
+            }),
+        }
+    }
+
+    /// Counts a session created by `ip` at `now`, returning `false` when `ip`
+    /// already created as many sessions as allowed in the current window.
+    pub(crate) fn allow(&self, ip: IpAddr, now: SystemTime) -> bool {
+        let window = self.window;
+        let current = |start: SystemTime| now >= start && now < start + window;
+        let mut counters = self.counters.lock().unwrap();
+        if counters.by_ip.len() >= counters.prune_at {
+            counters.by_ip.retain(|_, (start, _)| current(*start));
+            counters.prune_at = (2 * counters.by_ip.len()).max(1024);
+        }
+        let (start, count) = counters.by_ip.entry(ip).or_insert((now, 0));
+        if !current(*start) {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn allows_max_sessions_per_window() {
+        let limiter = CreationLimiter::new(2, Duration::from_secs(60), RateLimitAction::Reject);
+        let now = SystemTime::now();
+        assert!(limiter.allow(ip(1), now));
+        assert!(limiter.allow(ip(1), now + Duration::from_secs(1)));
+        assert!(!limiter.allow(ip(1), now + Duration::from_secs(2)));
+        // other addresses have their own count
+        assert!(limiter.allow(ip(2), now + Duration::from_secs(2)));
+        // a new window starts once the current one is over
+        assert!(limiter.allow(ip(1), now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn prunes_outdated_windows() {
+        let limiter = CreationLimiter::new(1, Duration::from_secs(60), RateLimitAction::Reject);
+        let now = SystemTime::now();
+        for i in 0..1024u32 {
+            assert!(limiter.allow(IpAddr::from(i.to_be_bytes()), now));
+        }
+        assert!(limiter.allow(ip(1), now + Duration::from_secs(120)));
+        assert_eq!(limiter.counters.lock().unwrap().by_ip.len(), 1);
+    }
+}
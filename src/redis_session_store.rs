@@ -0,0 +1,641 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use actix_web::{Error, web};
+use futures_util::future::{FutureExt, LocalBoxFuture, ok};
+use redis::{Client, Commands, Connection};
+
+use crate::clock::{Clock, millis, SystemClock};
+use crate::server_session_state::State;
+use crate::session_store::{LifetimeCap, SessionStore, SessionStoreError};
+
+struct RedisSessionStoreInner {
+    client: Client,
+    prefix: String,
+    clock: Arc<dyn Clock>,
+    max_lifetime: LifetimeCap,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl RedisSessionStoreInner {
+    fn key(&self, id: &str) -> String {
+        format!("{}{}", self.prefix, id)
+    }
+
+    fn principal_key(&self, principal: &str) -> String {
+        format!("{}principal:{}", self.prefix, principal)
+    }
+
+    /// Adds `id` to the index of the principal of `state`.
+    ///
+    /// The index is a sorted set of session ids by expiry time, which expires
+    /// with the last of its sessions.
+    fn index(&self, conn: &mut Connection, id: &str, state: &State) -> Result<(), SessionStoreError> {
+        let principal = match state.principal() {
+            Some(principal) => principal,
+            None => return Ok(()),
+        };
+        let index = self.principal_key(principal);
+        redis::pipe()
+            .zrembyscore(&index, "-inf", millis(self.clock.now())).ignore()
+            .zadd(&index, id, millis(state.expires_at())).ignore()
+            .query::<()>(conn)?;
+        let last: Vec<(String, i64)> = conn.zrange_withscores(&index, -1, -1)?;
+        if let Some((_, expires_at)) = last.first() {
+            conn.pexpire_at::<_, ()>(&index, *expires_at as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the state under `key` as used now, returning it if it exists.
+    ///
+    /// The key is watched while the state is updated, so a state saved in the
+    /// meantime is not overwritten: the update is retried on the new state.
+    fn touch(&self, conn: &mut Connection, key: &str) -> Result<Option<State>, SessionStoreError> {
+        loop {
+            redis::cmd("WATCH").arg(key).query::<()>(conn)?;
+            let mut state = match conn.get::<_, Option<Vec<u8>>>(key)? {
+                Some(value) => self.max_lifetime.apply(&State::from_bytes(&value)?),
+                None => {
+                    redis::cmd("UNWATCH").query::<()>(conn)?;
+                    return Ok(None);
+                }
+            };
+            let now = self.clock.now();
+            state.update_last_use_time(now);
+            let written: Option<()> = redis::pipe().atomic()
+                .set_ex(key, state.to_bytes()?, ttl(&state, now)).ignore()
+                .query(conn)?;
+            if written.is_some() {
+                return Ok(Some(state));
+            }
+        }
+    }
+
+    /// Runs `f` on an idle connection, opening a new one if none is available.
+    ///
+    /// A connection is only given back to the idle list when `f` succeeds.
+    fn with_connection<T, F>(&self, f: F) -> Result<T, SessionStoreError>
+        where
+            F: FnOnce(&mut Connection) -> Result<T, SessionStoreError>,
+    {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => self.client.get_connection()?,
+        };
+        let result = f(&mut conn)?;
+        self.idle.lock().unwrap().push(conn);
+        Ok(result)
+    }
+}
+
+/// Seconds until Redis drops the key holding `state`.
+fn ttl(state: &State, now: SystemTime) -> usize {
+    state.expires_at()
+        .duration_since(now)
+        .map(|ttl| ttl.as_secs())
+        .unwrap_or(0)
+        .max(1) as usize
+}
+
+/// Session store keeping states in Redis.
+///
+/// Every state is saved under a prefixed key with an expiry matching the session
+/// timeout, so Redis drops expired sessions by itself and several instances of
+/// an application can share their sessions. Sessions with a principal are also
+/// indexed in a sorted set under the `principal:` key of the prefix.
+///
+/// A maximum lifetime only shortens the expiry of the keys written after it
+/// is set.
+#[derive(Clone)]
+pub struct RedisSessionStore(Arc<RedisSessionStoreInner>);
+
+impl RedisSessionStore {
+    /// Construct new `RedisSessionStore` for the server at `url`, e.g. `redis://127.0.0.1/`.
+    ///
+    /// No connection is opened until the store is used.
+    pub fn new(url: &str) -> Result<RedisSessionStore, SessionStoreError> {
+        Ok(RedisSessionStore(Arc::new(RedisSessionStoreInner {
+            client: Client::open(url)?,
+            prefix: "session:".to_owned(),
+            clock: Arc::new(SystemClock),
+            max_lifetime: LifetimeCap::default(),
+            idle: Mutex::new(Vec::new()),
+        })))
+    }
+
+    /// Sets the clock used to compute key expiry. Default is `SystemClock`.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> RedisSessionStore {
+        Arc::get_mut(&mut self.0).unwrap().clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets the prefix of the keys holding session states. Default is `session:`.
+    pub fn prefix<S: Into<String>>(mut self, value: S) -> RedisSessionStore {
+        Arc::get_mut(&mut self.0).unwrap().prefix = value.into();
+        self
+    }
+}
+
+impl SessionStore for RedisSessionStore {
+    fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>> {
+        let inner = self.0.clone();
+        let key = inner.key(id);
+        async move {
+            let value = web::block(move || {
+                inner.with_connection(|conn| Ok(conn.get::<_, Option<Vec<u8>>>(&key)?))
+            }).await?;
+            match value {
+                Some(bytes) => Ok(Some(State::from_bytes(&bytes)?)),
+                None => Ok(None),
+            }
+        }.boxed_local()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let key = inner.key(id);
+        let id = id.to_owned();
+        async move {
+            let state = inner.max_lifetime.apply(state);
+            let value = state.to_bytes()?;
+            let ttl = ttl(&state, inner.clock.now());
+            web::block(move || {
+                inner.with_connection(|conn| {
+                    conn.set_ex::<_, _, ()>(&key, value, ttl)?;
+                    inner.index(conn, &id, &state)
+                })
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn touch<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let key = inner.key(id);
+        let id = id.to_owned();
+        async move {
+            web::block(move || {
+                inner.with_connection(|conn| {
+                    match inner.touch(conn, &key)? {
+                        Some(state) => inner.index(conn, &id, &state),
+                        None => Ok(()),
+                    }
+                })
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let key = inner.key(id);
+        async move {
+            web::block(move || {
+                inner.with_connection(|conn| Ok(conn.del::<_, ()>(&key)?))
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn rename<'a>(&'a self, old_id: &'a str, new_id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let (old_key, new_key) = (inner.key(old_id), inner.key(new_id));
+        let new_id = new_id.to_owned();
+        async move {
+            let state = inner.max_lifetime.apply(state);
+            let value = state.to_bytes()?;
+            let ttl = ttl(&state, inner.clock.now());
+            web::block(move || {
+                inner.with_connection(|conn| {
+                    redis::pipe().atomic()
+                        .set_ex(&new_key, value, ttl).ignore()
+                        .del(&old_key).ignore()
+                        .query::<()>(conn)?;
+                    inner.index(conn, &new_id, &state)
+                })
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn ids(&self) -> LocalBoxFuture<'_, Result<Vec<String>, Error>> {
+        let inner = self.0.clone();
+        async move {
+            let ids = web::block(move || {
+                inner.with_connection(|conn| {
+                    let index = inner.principal_key("");
+                    let keys: Vec<String> = conn.scan_match(format!("{}*", inner.prefix))?.collect();
+                    Ok(keys.into_iter()
+                        .filter(|key| !key.starts_with(&index))
+                        .map(|key| key[inner.prefix.len()..].to_owned())
+                        .collect::<Vec<_>>())
+                })
+            }).await?;
+            Ok(ids)
+        }.boxed_local()
+    }
+
+    fn sessions_of<'a>(&'a self, principal: &'a str) -> LocalBoxFuture<'a, Result<Vec<String>, Error>> {
+        let inner = self.0.clone();
+        let index = inner.principal_key(principal);
+        async move {
+            let ids = web::block(move || {
+                inner.with_connection(|conn| {
+                    let now = millis(inner.clock.now());
+                    let ids: Vec<String> = conn.zrangebyscore(&index, now, "+inf")?;
+                    // deleted sessions stay in the index until they would have expired
+                    let mut live = Vec::with_capacity(ids.len());
+                    for id in ids {
+                        if conn.exists(inner.key(&id))? {
+                            live.push(id);
+                        } else {
+                            conn.zrem::<_, _, ()>(&index, &id)?;
+                        }
+                    }
+                    Ok(live)
+                })
+            }).await?;
+            Ok(ids)
+        }.boxed_local()
+    }
+
+    fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>> {
+        let inner = self.0.clone();
+        let key = inner.key(id);
+        async move {
+            let exists = web::block(move || {
+                inner.with_connection(|conn| Ok(conn.exists(&key)?))
+            }).await?;
+            Ok(exists)
+        }.boxed_local()
+    }
+
+    fn set_max_lifetime(&self, max_lifetime: Option<Duration>) {
+        self.0.max_lifetime.set(max_lifetime);
+    }
+
+    fn shutdown(&self) -> LocalBoxFuture<'_, Result<(), Error>> {
+        self.0.idle.lock().unwrap().clear();
+        ok(()).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::clock::MockClock;
+    use crate::server_session_state::tests::{MINUTE, state_at};
+
+    use super::*;
+
+    /// Value of a key of `FakeRedis`.
+    enum Value {
+        String(Vec<u8>),
+        SortedSet(BTreeMap<Vec<u8>, f64>),
+    }
+
+    enum Reply {
+        Status(&'static str),
+        Integer(i64),
+        Bulk(Option<Vec<u8>>),
+        Array(Option<Vec<Reply>>),
+        Error(String),
+    }
+
+    impl Reply {
+        fn write(&self, out: &mut Vec<u8>) {
+            match self {
+                Reply::Status(status) => out.extend(format!("+{}\r\n", status).as_bytes()),
+                Reply::Integer(n) => out.extend(format!(":{}\r\n", n).as_bytes()),
+                Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+                Reply::Array(None) => out.extend(b"*-1\r\n"),
+                Reply::Bulk(Some(bytes)) => {
+                    out.extend(format!("${}\r\n", bytes.len()).as_bytes());
+                    out.extend(bytes);
+                    out.extend(b"\r\n");
+                }
+                Reply::Array(Some(items)) => {
+                    out.extend(format!("*{}\r\n", items.len()).as_bytes());
+                    for item in items {
+                        item.write(out);
+                    }
+                }
+                Reply::Error(message) => out.extend(format!("-ERR {}\r\n", message).as_bytes()),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Db {
+        keys: HashMap<Vec<u8>, (Value, Option<SystemTime>)>,
+        /// Bumped on every write of a key, for `WATCH`.
+        versions: HashMap<Vec<u8>, u64>,
+        /// Value written to a key right after its next `GET`, to interleave
+        /// a concurrent write.
+        after_get: Option<(Vec<u8>, Vec<u8>)>,
+    }
+
+    impl Db {
+        fn get(&mut self, key: &[u8]) -> Option<&mut Value> {
+            if matches!(self.keys.get(key), Some((_, Some(expiry))) if *expiry <= SystemTime::now()) {
+                self.keys.remove(key);
+            }
+            self.keys.get_mut(key).map(|(value, _)| value)
+        }
+
+        fn set(&mut self, key: &[u8], value: Value, expiry: Option<SystemTime>) {
+            self.keys.insert(key.to_vec(), (value, expiry));
+            self.touched(key);
+        }
+
+        fn touched(&mut self, key: &[u8]) {
+            *self.versions.entry(key.to_vec()).or_default() += 1;
+        }
+
+        fn sorted_set(&mut self, key: &[u8]) -> Option<&mut BTreeMap<Vec<u8>, f64>> {
+            match self.get(key) {
+                Some(Value::SortedSet(set)) => Some(set),
+                _ => None,
+            }
+        }
+
+        fn run(&mut self, args: &[Vec<u8>]) -> Reply {
+            let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+            let arg = |i: usize| String::from_utf8_lossy(&args[i]).into_owned();
+            let score = |i: usize| match arg(i).as_str() {
+                "-inf" => f64::NEG_INFINITY,
+                "+inf" | "inf" => f64::INFINITY,
+                score => score.parse().unwrap(),
+            };
+            match name.as_str() {
+                "GET" => match self.get(&args[1]) {
+                    Some(Value::String(value)) => Reply::Bulk(Some(value.clone())),
+                    _ => Reply::Bulk(None),
+                },
+                "SETEX" => {
+                    let expiry = SystemTime::now() + Duration::from_secs(arg(2).parse().unwrap());
+                    self.set(&args[1], Value::String(args[3].clone()), Some(expiry));
+                    Reply::Status("OK")
+                }
+                "DEL" => {
+                    let mut removed = 0;
+                    for key in &args[1..] {
+                        if self.get(key).is_some() {
+                            self.keys.remove(key);
+                            self.touched(key);
+                            removed += 1;
+                        }
+                    }
+                    Reply::Integer(removed)
+                }
+                "EXISTS" => Reply::Integer(self.get(&args[1]).is_some() as i64),
+                "PEXPIREAT" => {
+                    let expiry = SystemTime::UNIX_EPOCH + Duration::from_millis(arg(2).parse().unwrap());
+                    match self.keys.get_mut(&args[1]) {
+                        Some(entry) => {
+                            entry.1 = Some(expiry);
+                            Reply::Integer(1)
+                        }
+                        None => Reply::Integer(0),
+                    }
+                }
+                "ZADD" => {
+                    if self.get(&args[1]).is_none() {
+                        self.set(&args[1], Value::SortedSet(BTreeMap::new()), None);
+                    }
+                    let set = self.sorted_set(&args[1]).unwrap();
+                    let added = set.insert(args[3].clone(), score(2)).is_none();
+                    Reply::Integer(added as i64)
+                }
+                "ZREM" => {
+                    let removed = self.sorted_set(&args[1]).and_then(|set| set.remove(&args[2]));
+                    Reply::Integer(removed.is_some() as i64)
+                }
+                "ZREMRANGEBYSCORE" => {
+                    let (min, max) = (score(2), score(3));
+                    let set = match self.sorted_set(&args[1]) {
+                        Some(set) => set,
+                        None => return Reply::Integer(0),
+                    };
+                    let before = set.len();
+                    set.retain(|_, score| *score < min || *score > max);
+                    Reply::Integer((before - set.len()) as i64)
+                }
+                "ZRANGE" | "ZRANGEBYSCORE" => {
+                    let mut members: Vec<(Vec<u8>, f64)> = match self.sorted_set(&args[1]) {
+                        Some(set) => set.iter().map(|(member, score)| (member.clone(), *score)).collect(),
+                        None => Vec::new(),
+                    };
+                    members.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                    if name == "ZRANGEBYSCORE" {
+                        let (min, max) = (score(2), score(3));
+                        members.retain(|(_, score)| *score >= min && *score <= max);
+                        return Reply::Array(Some(members.into_iter().map(|(m, _)| Reply::Bulk(Some(m))).collect()));
+                    }
+                    let len = members.len() as i64;
+                    let index = |i: usize| {
+                        let i: i64 = arg(i).parse().unwrap();
+                        if i < 0 { len + i } else { i }
+                    };
+                    let (start, stop) = (index(2).max(0), index(3).min(len - 1));
+                    let mut items = Vec::new();
+                    for (member, score) in members.into_iter().skip(start as usize).take((stop - start + 1).max(0) as usize) {
+                        items.push(Reply::Bulk(Some(member)));
+                        items.push(Reply::Bulk(Some(format!("{}", score).into_bytes())));
+                    }
+                    Reply::Array(Some(items))
+                }
+                "SCAN" => {
+                    let prefix = arg(3).trim_end_matches('*').to_owned();
+                    let now = SystemTime::now();
+                    let keys = self.keys.iter()
+                        .filter(|(key, (_, expiry))| key.starts_with(prefix.as_bytes()) && !matches!(expiry, Some(e) if *e <= now))
+                        .map(|(key, _)| Reply::Bulk(Some(key.clone())))
+                        .collect();
+                    Reply::Array(Some(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(Some(keys))]))
+                }
+                _ => Reply::Error(format!("unknown command {}", name)),
+            }
+        }
+    }
+
+    /// Minimal in-process Redis speaking RESP, with the commands the store uses.
+    #[derive(Clone)]
+    struct FakeRedis {
+        db: Arc<Mutex<Db>>,
+        url: String,
+    }
+
+    impl FakeRedis {
+        fn start() -> FakeRedis {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("redis://{}/", listener.local_addr().unwrap());
+            let db = Arc::new(Mutex::new(Db::default()));
+            let server = FakeRedis { db, url };
+            let accepting = server.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let server = accepting.clone();
+                    thread::spawn(move || server.serve(stream?));
+                }
+                Ok::<_, io::Error>(())
+            });
+            server
+        }
+
+        fn serve(&self, stream: TcpStream) -> io::Result<()> {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+            let mut watched: Option<HashMap<Vec<u8>, u64>> = None;
+            let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+            while let Some(args) = read_command(&mut reader)? {
+                let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+                let mut db = self.db.lock().unwrap();
+                let reply = match (name.as_str(), &mut queued) {
+                    ("MULTI", _) => {
+                        queued = Some(Vec::new());
+                        Reply::Status("OK")
+                    }
+                    ("EXEC", Some(_)) => {
+                        let commands = queued.take().unwrap();
+                        let changed = matches!(watched.take(), Some(versions)
+                            if versions.iter().any(|(key, version)| db.versions.get(key).copied().unwrap_or(0) != *version));
+                        if changed {
+                            Reply::Array(None)
+                        } else {
+                            Reply::Array(Some(commands.iter().map(|args| db.run(args)).collect()))
+                        }
+                    }
+                    (_, Some(commands)) => {
+                        commands.push(args);
+                        Reply::Status("QUEUED")
+                    }
+                    ("WATCH", None) => {
+                        let versions = watched.get_or_insert_with(HashMap::new);
+                        for key in &args[1..] {
+                            versions.insert(key.clone(), db.versions.get(key).copied().unwrap_or(0));
+                        }
+                        Reply::Status("OK")
+                    }
+                    ("UNWATCH", None) => {
+                        watched = None;
+                        Reply::Status("OK")
+                    }
+                    (_, None) => {
+                        let reply = db.run(&args);
+                        if name == "GET" && matches!(&db.after_get, Some((key, _)) if *key == args[1]) {
+                            let (key, value) = db.after_get.take().unwrap();
+                            db.set(&key, Value::String(value), Some(SystemTime::now() + Duration::from_secs(60)));
+                        }
+                        reply
+                    }
+                };
+                let mut out = Vec::new();
+                reply.write(&mut out);
+                writer.write_all(&out)?;
+            }
+            Ok(())
+        }
+
+        fn store(&self) -> RedisSessionStore {
+            RedisSessionStore::new(&self.url).unwrap()
+        }
+    }
+
+    /// Reads a command sent as an array of bulk strings, `None` once the client disconnected.
+    fn read_command(reader: &mut BufReader<TcpStream>) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let count: usize = line.trim_end()[1..].parse().unwrap();
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let len: usize = line.trim_end()[1..].parse().unwrap();
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Ok(Some(args))
+    }
+
+    fn state(key: &str, value: &str) -> State {
+        let mut state = State::new(Duration::from_secs(60));
+        state.set(key, &value).unwrap();
+        state
+    }
+
+    #[actix_rt::test]
+    async fn saves_loads_and_deletes() {
+        let store = FakeRedis::start().store();
+        assert!(store.load("a").await.unwrap().is_none());
+        store.save("a", &state("user", "alice")).await.unwrap();
+        assert!(store.exists("a").await.unwrap());
+        let loaded = store.load("a").await.unwrap().unwrap();
+        assert_eq!(loaded.get::<String>("user").unwrap().as_deref(), Some("alice"));
+        store.delete("a").await.unwrap();
+        assert!(!store.exists("a").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn touch_updates_last_use_time() {
+        let clock = MockClock::default();
+        let store = FakeRedis::start().store().clock(clock.clone());
+        store.save("a", &state("user", "alice")).await.unwrap();
+        clock.advance(Duration::from_secs(30));
+        store.touch("a").await.unwrap();
+        assert_eq!(millis(store.load("a").await.unwrap().unwrap().last_use_time()), millis(clock.now()));
+        // touching a missing session doesn't create it
+        store.touch("b").await.unwrap();
+        assert!(!store.exists("b").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn touch_keeps_a_state_saved_concurrently() {
+        let redis = FakeRedis::start();
+        let store = redis.store();
+        store.save("a", &state("user", "alice")).await.unwrap();
+        let saved = state("user", "bob").to_bytes().unwrap();
+        redis.db.lock().unwrap().after_get = Some((b"session:a".to_vec(), saved));
+        store.touch("a").await.unwrap();
+        let loaded = store.load("a").await.unwrap().unwrap();
+        assert_eq!(loaded.get::<String>("user").unwrap().as_deref(), Some("bob"));
+    }
+
+    #[actix_rt::test]
+    async fn rename_moves_the_state() {
+        let store = FakeRedis::start().store();
+        store.save("old", &state("user", "alice")).await.unwrap();
+        store.rename("old", "new", &state("user", "alice")).await.unwrap();
+        assert!(!store.exists("old").await.unwrap());
+        assert!(store.exists("new").await.unwrap());
+        assert_eq!(store.ids().await.unwrap(), vec!["new".to_owned()]);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_of_lists_live_sessions_of_the_principal() {
+        let clock = MockClock::default();
+        let store = FakeRedis::start().store().clock(clock.clone());
+        for (id, principal, timeout) in [("a", "alice", MINUTE), ("b", "alice", 10 * MINUTE), ("c", "bob", MINUTE)] {
+            let mut state = state_at(&clock, timeout);
+            state.set_principal(principal.to_owned());
+            store.save(id, &state).await.unwrap();
+        }
+        let mut ids = store.sessions_of("alice").await.unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b"]);
+        clock.advance(2 * MINUTE);
+        assert_eq!(store.sessions_of("alice").await.unwrap(), vec!["b"]);
+        store.delete("b").await.unwrap();
+        assert!(store.sessions_of("alice").await.unwrap().is_empty());
+    }
+}
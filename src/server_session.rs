@@ -1,64 +1,119 @@
+use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::RwLock;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use actix_service::{Service, Transform};
-use actix_web::cookie::{SameSite};
+use actix_web::cookie::{Key, SameSite};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
-use futures_util::future::{FutureExt, LocalBoxFuture, ok, Ready};
-use lazy_static::lazy_static;
+use actix_web::Error;
+use futures_util::future::{err, FutureExt, LocalBoxFuture, ok, Ready};
 
-use crate::server_session_inner::{CookieSecurity, ServerSessionInner};
-use crate::server_session_state::ServerSessionState;
+use crate::client_binding::{BindingAction, ClientBinding};
+use crate::clock::Clock;
+use crate::id_generator::IdGenerator;
+use crate::rate_limit::{CreationLimiter, RateLimitAction, RateLimitError};
+use crate::server_session_inner::{CookiePrefix, CookiePrefixError, CookieSecurity, ServerSessionInner};
+use crate::server_session_state::{EvictionPolicy, ServerSessionState, State};
 use crate::session::{Session, SessionStatus};
+use crate::session_codec::SessionCodec;
+use crate::session_hooks::{call_hooks, SessionHook};
+use crate::session_limit::{SessionLimitError, SessionLimitPolicy};
+use crate::session_manager::SessionManager;
+use crate::session_store::SessionStore;
 
-lazy_static! {
-    static ref STATE_SERVER: RwLock<ServerSessionState> = RwLock::new(ServerSessionState::new());
-}
-
-pub struct ServerSession(Rc<ServerSessionInner>);
+/// Server side session middleware.
+///
+/// Every `ServerSession` owns its own store and configuration, so several of them
+/// can be used in one `App`. Build it once and clone it into the `HttpServer`
+/// factory to share the store between workers.
+///
+/// Sessions are saved under a keyed hash of their id, derived from the cookie
+/// key, so the contents of a store can't be replayed as cookies. Sessions
+/// saved under their raw id by earlier versions aren't found anymore, their
+/// clients get a new session.
+#[derive(Clone)]
+pub struct ServerSession(Arc<ServerSessionInner>);
 
 impl ServerSession {
 
-    fn new(inner: ServerSessionInner) -> ServerSession {
-        STATE_SERVER.write().unwrap().start();
-        ServerSession(Rc::new(inner))
+    fn new(key: &[u8], security: CookieSecurity) -> ServerSession {
+        let store = ServerSessionState::new();
+        store.start();
+        ServerSession(Arc::new(ServerSessionInner::new(
+            key,
+            security,
+            Arc::new(store),
+        )))
     }
     /// Construct new *signed* `CookieSessionBackend` instance.
     ///
     /// Panics if key length is less than 32 bytes.
     pub fn signed(key: &[u8]) -> ServerSession {
-        ServerSession::new(ServerSessionInner::new(
-            key,
-            CookieSecurity::Signed,
-        ))
+        ServerSession::new(key, CookieSecurity::Signed)
     }
 
     /// Construct new *private* `ServerSessionBackend` instance.
     ///
     /// Panics if key length is less than 32 bytes.
     pub fn private(key: &[u8]) -> ServerSession {
-        ServerSession::new(ServerSessionInner::new(
-            key,
-            CookieSecurity::Private,
-        ))
+        ServerSession::new(key, CookieSecurity::Private)
+    }
+
+    /// Sets the keys of previous deployments.
+    ///
+    /// Cookies signed or encrypted with a retired key are still accepted and
+    /// re-issued with the current key, so rotating the key doesn't end every
+    /// session. Panics if a key length is less than 32 bytes.
+    pub fn retired_keys<I, K>(mut self, keys: I) -> ServerSession
+        where
+            I: IntoIterator<Item=K>,
+            K: AsRef<[u8]>,
+    {
+        Arc::get_mut(&mut self.0).unwrap().retired_keys = keys
+            .into_iter()
+            .map(|key| Key::derive_from(key.as_ref()))
+            .collect();
+        self
     }
 
     /// Sets the `path` field in the session cookie being built.
     pub fn path<S: Into<String>>(mut self, value: S) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().path = value.into();
+        Arc::get_mut(&mut self.0).unwrap().path = value.into();
         self
     }
 
-    /// Sets the `name` field in the session cookie being built.
+    /// Sets the `name` field in the session cookie being built, after the
+    /// prefix set with `prefix` if any.
+    ///
+    /// A name starting with `__Host-` or `__Secure-` is validated like `prefix`.
     pub fn name<S: Into<String>>(mut self, value: S) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().name = value.into();
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        let prefix = inner.prefix.map(|prefix| prefix.as_str()).unwrap_or_default();
+        inner.name = format!("{}{}", prefix, value.into());
+        self
+    }
+
+    /// Prefixes the session cookie name with `value` and makes the cookie
+    /// secure, browsers then refuse the cookie from insecure origins.
+    ///
+    /// Check the cookie attributes against the rules of the prefix with
+    /// `validate`, e.g. `CookiePrefix::Host` forbids a domain.
+    pub fn prefix(mut self, value: CookiePrefix) -> ServerSession {
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        let name = inner.prefix
+            .and_then(|prefix| inner.name.strip_prefix(prefix.as_str()))
+            .unwrap_or(&inner.name);
+        inner.name = format!("{}{}", value.as_str(), name);
+        inner.prefix = Some(value);
+        inner.secure = true;
         self
     }
 
     /// Sets the `domain` field in the session cookie being built.
     pub fn domain<S: Into<String>>(mut self, value: S) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().domain = Some(value.into());
+        Arc::get_mut(&mut self.0).unwrap().domain = Some(value.into());
         self
     }
 
@@ -67,7 +122,7 @@ impl ServerSession {
     ///
     /// Useful when trying to comply with laws that require consent for setting cookies.
     pub fn lazy(mut self, value: bool) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().lazy = value;
+        Arc::get_mut(&mut self.0).unwrap().lazy = value;
         self
     }
 
@@ -76,19 +131,19 @@ impl ServerSession {
     /// If the `secure` field is set, a cookie will only be transmitted when the
     /// connection is secure - i.e. `https`
     pub fn secure(mut self, value: bool) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().secure = value;
+        Arc::get_mut(&mut self.0).unwrap().secure = value;
         self
     }
 
     /// Sets the `http_only` field in the session cookie being built.
     pub fn http_only(mut self, value: bool) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().http_only = value;
+        Arc::get_mut(&mut self.0).unwrap().http_only = value;
         self
     }
 
     /// Sets the `same_site` field in the session cookie being built.
     pub fn same_site(mut self, value: SameSite) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().same_site = Some(value);
+        Arc::get_mut(&mut self.0).unwrap().same_site = Some(value);
         self
     }
 
@@ -99,7 +154,7 @@ impl ServerSession {
 
     /// Sets the `max-age` field in the session cookie being built.
     pub fn max_age_time(mut self, value: time::Duration) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().max_age = Some(value);
+        Arc::get_mut(&mut self.0).unwrap().max_age = Some(value);
         self
     }
 
@@ -110,57 +165,235 @@ impl ServerSession {
 
     /// Sets the `expires` field in the session cookie being built.
     pub fn expires_in_time(mut self, value: time::Duration) -> ServerSession {
-        Rc::get_mut(&mut self.0).unwrap().expires_in = Some(value);
+        Arc::get_mut(&mut self.0).unwrap().expires_in = Some(value);
         self
     }
 
     /// Set default session timeout
     ///
-    pub fn set_timeout(self, minutes: u64) -> ServerSession {
-        STATE_SERVER.write().unwrap().set_timeout(minutes);
+    pub fn set_timeout(mut self, minutes: u64) -> ServerSession {
+        Arc::get_mut(&mut self.0).unwrap().timeout = Duration::from_secs(minutes * 60);
+        self
+    }
+
+    /// Set maximum session lifetime
+    ///
+    /// Sessions expire `minutes` after their creation, even when they are
+    /// used within the timeout. The cap also applies to sessions already
+    /// saved, whatever their own maximum. Default is no maximum.
+    pub fn set_max_lifetime(mut self, minutes: u64) -> ServerSession {
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        inner.max_lifetime = Some(Duration::from_secs(minutes * 60));
+        inner.store.set_max_lifetime(inner.max_lifetime);
+        self
+    }
+
+    /// Sets the codec encoding the values of new sessions. Default is JSON.
+    ///
+    /// Stores keeping states as bytes encode them with the same codec.
+    pub fn codec(mut self, value: SessionCodec) -> ServerSession {
+        Arc::get_mut(&mut self.0).unwrap().codec = value;
+        self
+    }
+
+    /// Sets the store holding the session states.
+    ///
+    /// Default is the in-memory `ServerSessionState`. The store is used as
+    /// given, stores sweeping expired sessions in the background must be
+    /// started first, e.g. with `ServerSessionState::start`.
+    pub fn store<T: SessionStore + 'static>(mut self, store: Arc<T>) -> ServerSession {
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        inner.set_store(store);
+        inner.default_store = false;
+        self
+    }
+
+    /// Sets the clock deciding when sessions and cookies expire. Default is `SystemClock`.
+    ///
+    /// The default store is given the same clock, a store set with `store`
+    /// should be built with it too.
+    pub fn clock<C: Clock + Clone + 'static>(mut self, clock: C) -> ServerSession {
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        inner.clock = Arc::new(clock);
+        if inner.default_store {
+            inner.reset_default_store();
+        }
+        self
+    }
+
+    /// Caps the number of sessions of the default store, see
+    /// `ServerSessionState::capacity`. Default is no cap.
+    ///
+    /// It has no effect on a store set with `store`, which should be built
+    /// with its own capacity. Panics if `max` is zero.
+    pub fn capacity(mut self, max: usize, policy: EvictionPolicy) -> ServerSession {
+        assert!(max > 0, "capacity must not be zero");
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        inner.capacity = Some((max, policy));
+        if inner.default_store {
+            inner.reset_default_store();
+        }
+        self
+    }
+
+    /// Sets the generator of new session ids. Default is `RandomIdGenerator`.
+    pub fn id_generator<G: IdGenerator + 'static>(mut self, generator: G) -> ServerSession {
+        Arc::get_mut(&mut self.0).unwrap().id_generator = Arc::new(generator);
+        self
+    }
+
+    /// Sets a prefix added to every new session id, e.g. an environment tag
+    /// so ids can be told apart in logs. Default is no prefix.
+    ///
+    /// Panics if `value` contains other characters than `A-Z`, `a-z`, `0-9`, `-` and `_`.
+    pub fn id_prefix<S: Into<String>>(mut self, value: S) -> ServerSession {
+        let value = value.into();
+        assert!(
+            value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "session id prefix may only contain A-Z, a-z, 0-9, '-' and '_'"
+        );
+        Arc::get_mut(&mut self.0).unwrap().id_prefix = value;
+        self
+    }
+
+    /// Binds sessions to properties of the client that created them.
+    /// Default is no binding.
+    pub fn client_binding(mut self, value: ClientBinding) -> ServerSession {
+        Arc::get_mut(&mut self.0).unwrap().binding = Some(value);
+        self
+    }
+
+    /// Limits how many sessions a principal may have at once, see
+    /// `Session::set_principal`. Default is no limit.
+    ///
+    /// The limit is enforced when a request sets the principal of its session,
+    /// the store must support the principal index. Panics if `max` is zero.
+    pub fn session_limit(mut self, max: usize, policy: SessionLimitPolicy) -> ServerSession {
+        assert!(max > 0, "session limit must not be zero");
+        Arc::get_mut(&mut self.0).unwrap().session_limit = Some((max, policy));
+        self
+    }
+
+    /// Limits how many sessions each IP address may create per `window`,
+    /// taking `action` on requests beyond the limit. Default is no limit.
+    ///
+    /// The address is the address of the peer, behind a proxy it is the
+    /// address of the proxy.
+    pub fn creation_rate_limit(mut self, max: u32, window: Duration, action: RateLimitAction) -> ServerSession {
+        Arc::get_mut(&mut self.0).unwrap().rate_limit = Some(CreationLimiter::new(max, window, action));
+        self
+    }
+
+    /// Returns a handle revoking sessions of this middleware from anywhere.
+    ///
+    /// Call it once the middleware is configured, builder methods panic
+    /// while a `SessionManager` or a clone of the middleware is alive.
+    pub fn manager(&self) -> SessionManager {
+        SessionManager::new(self.0.clone())
+    }
+
+    /// Checks the cookie attributes against the rules of the `__Host-` or
+    /// `__Secure-` prefix of the cookie name, browsers drop cookies breaking
+    /// them.
+    ///
+    /// Call it once configured, before `HttpServer::new`. A middleware failing
+    /// the check fails to start in every worker instead.
+    pub fn validate(&self) -> Result<(), CookiePrefixError> {
+        self.0.check_prefix()
+    }
+
+    /// Stops the background work of the store, e.g. once the server stopped.
+    ///
+    /// The store also stops on its own when the last `ServerSession` using it
+    /// is dropped.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.0.store.shutdown().await
+    }
+
+    /// Registers a hook called when a new session is saved for the first time.
+    pub fn on_create<F>(mut self, hook: F) -> ServerSession
+        where
+            F: Fn(&str, &State) + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.0).unwrap().hooks.on_create.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a hook called when a session expires.
+    ///
+    /// It is called when a request comes with an expired session, and when
+    /// the store removes expired sessions if it supports it.
+    pub fn on_expire<F>(mut self, hook: F) -> ServerSession
+        where
+            F: Fn(&str, &State) + Send + Sync + 'static,
+    {
+        let inner = Arc::get_mut(&mut self.0).unwrap();
+        let hook: SessionHook = Arc::new(hook);
+        inner.store.on_expire(hook.clone());
+        inner.hooks.on_expire.push(hook);
+        self
+    }
+
+    /// Registers a hook called when a session is removed with `Session::purge`,
+    /// to respect the session limit of its principal or by `SessionManager`.
+    pub fn on_purge<F>(mut self, hook: F) -> ServerSession
+        where
+            F: Fn(&str, &State) + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.0).unwrap().hooks.on_purge.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers a hook called when a session is renewed with `Session::renew`.
+    pub fn on_renew<F>(mut self, hook: F) -> ServerSession
+        where
+            F: Fn(&str, &State) + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.0).unwrap().hooks.on_renew.push(Arc::new(hook));
         self
     }
 }
 
 impl<S, B: 'static> Transform<S> for ServerSession
     where
-        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>>,
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
         S::Future: 'static,
-        S::Error: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
-    type Error = S::Error;
+    type Error = Error;
     type Transform = ServerSessionMiddleware<S>;
     type InitError = ();
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
+        if self.0.check_prefix().is_err() {
+            return err(());
+        }
         ok(ServerSessionMiddleware {
-            service,
+            service: Rc::new(RefCell::new(service)),
             inner: self.0.clone(),
         })
     }
 }
 
 pub struct ServerSessionMiddleware<S> {
-    service: S,
-    inner: Rc<ServerSessionInner>,
+    service: Rc<RefCell<S>>,
+    inner: Arc<ServerSessionInner>,
 }
 
 impl<S, B: 'static> Service for ServerSessionMiddleware<S>
     where
-        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>>,
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error> + 'static,
         S::Future: 'static,
-        S::Error: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
-    type Error = S::Error;
+    type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     /// On first request, a new session cookie is returned in response, regardless
@@ -169,50 +402,610 @@ impl<S, B: 'static> Service for ServerSessionMiddleware<S>
     /// a user logs out, call session.purge() to set SessionStatus accordingly
     /// and this will trigger removal of the session cookie in the response.
     fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
         let inner = self.inner.clone();
-        let (mut is_new, mut id) = inner.get_session_id(&req);
-
-        if let Some(state) = STATE_SERVER.read().unwrap().get_state(&id) {
-            Session::set_session(state, &mut req);
-        } else {
-            is_new = true;
-            id = inner.generate_id();
-            Session::set_session(STATE_SERVER.read().unwrap().new_state(), &mut req);
-        }
 
-        let fut = self.service.call(req);
+        async move {
+            let store = &inner.store;
+            let (mut id, stale_key) = match inner.get_session_id(&req) {
+                Some((id, stale_key)) => (Some(id), stale_key),
+                None => (None, false),
+            };
+
+            let mut loaded = None;
+            if let Some(ref id) = id {
+                let store_id = inner.store_id(id);
+                loaded = store.load(&store_id).await?;
+                if loaded.is_none() && stale_key {
+                    // sessions from before the key rotation are saved under the old hash
+                    for old_id in inner.retired_store_ids(id) {
+                        if let Some(state) = store.load(&old_id).await? {
+                            store.rename(&old_id, &store_id, &state).await?;
+                            loaded = Some(state);
+                            break;
+                        }
+                    }
+                }
+            }
+            if let Some(ref mut state) = loaded {
+                state.cap_lifetime(inner.max_lifetime);
+            }
+            let fingerprint = inner.fingerprint(&req);
+            let mut mismatch = None;
+            let mut rebound = false;
+            if let (Some(binding), Some(fingerprint), Some(state)) = (&inner.binding, &fingerprint, &mut loaded) {
+                if !state.is_expired(inner.clock.now()) && state.client() != Some(fingerprint.as_str()) {
+                    // fingerprints made with a retired key are rebound to the current key
+                    match state.client() {
+                        Some(client) if !inner.retired_fingerprint(&req, client) => {
+                            mismatch = Some(binding.action())
+                        }
+                        _ => {}
+                    }
+                    // a flagged session stays bound to its first client
+                    if mismatch != Some(BindingAction::Flag) {
+                        state.bind_client(fingerprint.clone());
+                        rebound = true;
+                    }
+                }
+            }
 
-        let fut = async move {
-            fut.await.map(|mut res| {
-                if is_new {
-                    inner.set_cookie(&mut res, id.clone());
+            let mut principal = None;
+            let mut ephemeral = false;
+            let is_new = match loaded {
+                Some(state) if !state.is_expired(inner.clock.now()) && mismatch != Some(BindingAction::Purge) => {
+                    principal = state.principal().map(ToOwned::to_owned);
+                    Session::set_session(state, &mut req);
+                    false
                 }
-                match Session::get_changes(&mut res) {
-                    (SessionStatus::Changed, Some(state))
-                    | (SessionStatus::Renewed, Some(state)) => {
-                        res.checked_expr(|_| {
-                            STATE_SERVER.write().unwrap().set_state(&id, &state)
-                        })
+                loaded => {
+                    // never reuse an id the store doesn't know
+                    if let (Some(state), Some(id)) = (loaded, id.take()) {
+                        let id = inner.store_id(&id);
+                        store.delete(&id).await?;
+                        let hooks = match mismatch {
+                            Some(_) => &inner.hooks.on_purge,
+                            None => &inner.hooks.on_expire,
+                        };
+                        call_hooks(hooks, &id, &state);
+                    }
+                    if let (Some(limiter), Some(addr)) = (&inner.rate_limit, req.peer_addr()) {
+                        if !limiter.allow(addr.ip(), inner.clock.now()) {
+                            match limiter.action {
+                                RateLimitAction::Reject => return Ok(req.error_response(RateLimitError)),
+                                RateLimitAction::ServeWithoutSession => ephemeral = true,
+                            }
+                        }
                     }
-                    (SessionStatus::Unchanged, Some(state)) => {
-                        res.checked_expr(|_| {
-                            STATE_SERVER.write().unwrap().set_state(&id, &state)
-                        })
+                    let mut state = State::with_codec(inner.timeout, inner.codec);
+                    state.start_at(inner.clock.now());
+                    state.update_max_lifetime(inner.max_lifetime);
+                    if let Some(fingerprint) = fingerprint {
+                        state.bind_client(fingerprint);
+                    }
+                    Session::set_session(state, &mut req);
+                    true
+                }
+            };
+            if let Some(action) = mismatch {
+                Session::set_client_mismatch(action, &mut req);
+            }
+            let id = match id {
+                Some(id) => id,
+                None if ephemeral => String::new(),
+                None => inner.generate_id().await?,
+            };
+
+            let fut = srv.borrow_mut().call(req);
+            let mut res = fut.await?;
+            if ephemeral {
+                return Ok(res);
+            }
+            let store_id = inner.store_id(&id);
+
+            let (status, state) = Session::get_changes(&mut res);
+            // setting a new principal is a login, subject to the session limit
+            let login = match state {
+                Some(ref state) if status != SessionStatus::Purged => {
+                    state.principal().filter(|new| principal.as_deref() != Some(*new))
+                }
+                _ => None,
+            };
+            if let Some(login) = login {
+                if !inner.admit(&store_id, login).await? {
+                    return Ok(res.error_response(SessionLimitError));
+                }
+            }
+            // renewed and purged sessions send their own cookie
+            let reissue = stale_key && matches!(status, SessionStatus::Changed | SessionStatus::Unchanged);
+            if is_new || reissue {
+                let _ = inner.set_cookie(&mut res, id.clone());
+            }
+            let result = match (status, state) {
+                (SessionStatus::Purged, state) => {
+                    let _ = inner.remove_cookie(&mut res);
+                    let result = store.delete(&store_id).await;
+                    match state {
+                        Some(state) if !is_new => call_hooks(&inner.hooks.on_purge, &store_id, &state),
+                        _ => {}
                     }
-                    (SessionStatus::Unchanged, _) => {
-                        // set a new session cookie upon first request (new client)
-                        res
+                    result
+                }
+                (SessionStatus::Unchanged, Some(_)) if !is_new && !rebound => {
+                    store.touch(&store_id).await
+                }
+                (SessionStatus::Renewed, Some(mut state)) if !is_new => {
+                    // move the state to a fresh id so a fixated id becomes useless
+                    match inner.generate_id().await {
+                        Ok(new_id) => {
+                            state.update_last_use_time(inner.clock.now());
+                            let new_store_id = inner.store_id(&new_id);
+                            let result = store.rename(&store_id, &new_store_id, &state).await;
+                            if result.is_ok() {
+                                let _ = inner.set_cookie(&mut res, new_id);
+                                call_hooks(&inner.hooks.on_renew, &new_store_id, &state);
+                            }
+                            result
+                        }
+                        Err(err) => Err(err),
                     }
-                    (SessionStatus::Purged, _) => {
-                        let _ = inner.remove_cookie(&mut res);
-                        let _ = STATE_SERVER.write().unwrap().remove_state(&id);
-                        res
+                }
+                (status, Some(mut state)) => {
+                    state.update_last_use_time(inner.clock.now());
+                    let result = store.save(&store_id, &state).await;
+                    if result.is_ok() {
+                        if is_new {
+                            call_hooks(&inner.hooks.on_create, &store_id, &state);
+                        }
+                        if status == SessionStatus::Renewed {
+                            call_hooks(&inner.hooks.on_renew, &store_id, &state);
+                        }
                     }
-                    _ => res,
+                    result
                 }
-            })
-        }.boxed_local();
+                // set a new session cookie upon first request (new client)
+                _ => Ok(()),
+            };
+            Ok(res.checked_expr(|_| result))
+        }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use actix_http::Request;
+    use actix_web::{App, HttpResponse, test, web};
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::MessageBody;
+    use actix_web::http::header::SET_COOKIE;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    /// Counts the requests of the session.
+    pub(crate) async fn count(session: Session) -> HttpResponse {
+        let count = session.get::<i32>("count").unwrap().unwrap_or(0) + 1;
+        session.set("count", count).unwrap();
+        HttpResponse::Ok().body(count.to_string())
+    }
+
+    pub(crate) async fn purge(session: Session) -> HttpResponse {
+        session.purge();
+        HttpResponse::Ok().finish()
+    }
+
+    pub(crate) async fn renew(session: Session) -> HttpResponse {
+        session.renew();
+        count(session).await
+    }
+
+    /// Signs in as the principal named in the path.
+    pub(crate) async fn login(session: Session, name: web::Path<String>) -> HttpResponse {
+        session.set_principal(name.into_inner());
+        HttpResponse::Ok().finish()
+    }
+
+    async fn principal(session: Session) -> HttpResponse {
+        HttpResponse::Ok().body(session.principal().unwrap_or_default())
+    }
+
+    fn request(uri: &str, cookie: Option<&Cookie<'static>>) -> Request {
+        let req = test::TestRequest::get().uri(uri);
+        match cookie {
+            Some(cookie) => req.cookie(cookie.clone()).to_request(),
+            None => req.to_request(),
+        }
+    }
+
+    /// Sends a request to `app`, returning the new session cookie, if any, and the body.
+    pub(crate) async fn send<S, B>(app: &mut S, cookie: Option<&Cookie<'static>>) -> (Option<Cookie<'static>>, String)
+        where
+            S: Service<Request=Request, Response=ServiceResponse<B>, Error=Error>,
+            B: MessageBody + Unpin,
+    {
+        send_to(app, "/", cookie).await
+    }
+
+    /// Like `send`, to `uri`.
+    pub(crate) async fn send_to<S, B>(app: &mut S, uri: &str, cookie: Option<&Cookie<'static>>) -> (Option<Cookie<'static>>, String)
+        where
+            S: Service<Request=Request, Response=ServiceResponse<B>, Error=Error>,
+            B: MessageBody + Unpin,
+    {
+        let res = test::call_service(app, request(uri, cookie)).await;
+        let cookie = res.response().cookies().next().map(|cookie| cookie.into_owned());
+        let body = test::read_body(res).await;
+        (cookie, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn max_lifetime_caps_sessions_saved_before() {
+        let clock = MockClock::default();
+        let store = Arc::new(ServerSessionState::new().clock(clock.clone()));
+        let uncapped = ServerSession::signed(&[0; 32]).clock(clock.clone()).store(store.clone());
+        let mut app = test::init_service(App::new().wrap(uncapped).route("/", web::get().to(count))).await;
+        let (cookie, body) = send(&mut app, None).await;
+        assert_eq!(body, "1");
+
+        let capped = ServerSession::signed(&[0; 32]).clock(clock.clone()).store(store).set_max_lifetime(45);
+        let mut app = test::init_service(App::new().wrap(capped).route("/", web::get().to(count))).await;
+        clock.advance(Duration::from_secs(20 * 60));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "2");
+        clock.advance(Duration::from_secs(20 * 60));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "3");
+        clock.advance(Duration::from_secs(20 * 60));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "1");
+    }
 
-        fut
+    #[actix_rt::test]
+    async fn prefix_rules_allow_any_builder_order() {
+        let session = ServerSession::signed(&[0; 32]).name("__Secure-sid").secure(true);
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let res = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        let cookie = res.response().cookies().next().unwrap();
+        assert_eq!(cookie.name(), "__Secure-sid");
+        assert_eq!(cookie.secure(), Some(true));
+    }
+
+    #[test]
+    fn host_prefix_rejects_domain_and_path() {
+        let session = ServerSession::signed(&[0; 32]).domain("example.com").prefix(CookiePrefix::Host);
+        assert_eq!(session.validate(), Err(CookiePrefixError::Domain));
+        let session = ServerSession::signed(&[0; 32]).prefix(CookiePrefix::Host).path("/app");
+        assert_eq!(session.validate(), Err(CookiePrefixError::Path));
+        assert!(ServerSession::signed(&[0; 32]).prefix(CookiePrefix::Host).validate().is_ok());
+    }
+
+    #[test]
+    fn secure_prefix_rejects_insecure_cookie() {
+        let session = ServerSession::signed(&[0; 32]).prefix(CookiePrefix::Secure).secure(false);
+        let error = session.validate().unwrap_err();
+        assert_eq!(error, CookiePrefixError::Insecure(CookiePrefix::Secure));
+        assert_eq!(error.to_string(), "__Secure- session cookies must be secure");
+        let session = ServerSession::signed(&[0; 32]).name("__Secure-sid");
+        assert_eq!(session.validate(), Err(CookiePrefixError::Insecure(CookiePrefix::Secure)));
+    }
+
+    #[actix_rt::test]
+    async fn invalid_prefix_fails_to_start() {
+        let session = ServerSession::signed(&[0; 32]).prefix(CookiePrefix::Secure).secure(false);
+        let service = actix_service::fn_service(|req: ServiceRequest| {
+            ok::<_, Error>(req.into_response(HttpResponse::Ok().finish()))
+        });
+        assert!(session.new_transform(service).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn purge_removes_prefixed_cookie() {
+        let session = ServerSession::signed(&[0; 32]).prefix(CookiePrefix::Host).same_site(SameSite::Strict);
+        let mut app = test::init_service(App::new().wrap(session)
+            .route("/", web::get().to(count))
+            .route("/purge", web::get().to(purge))).await;
+        let res = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::get().uri("/purge").cookie(cookie).to_request();
+        let res = test::call_service(&mut app, req).await;
+        let removal = res.headers().get(SET_COOKIE).unwrap().to_str().unwrap();
+        assert!(removal.starts_with("__Host-actix-session=;"));
+        assert!(removal.contains("Secure") && removal.contains("Path=/") && removal.contains("SameSite=Strict"));
+        assert!(removal.contains("Max-Age=0"));
+    }
+
+    #[actix_rt::test]
+    async fn creation_rate_limit() {
+        let peer = "10.0.0.1:1234".parse().unwrap();
+        for (action, status) in [(RateLimitAction::Reject, 429), (RateLimitAction::ServeWithoutSession, 200)] {
+            let session = ServerSession::signed(&[0; 32]).creation_rate_limit(1, Duration::from_secs(60), action);
+            let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+            let res = test::call_service(&mut app, test::TestRequest::get().peer_addr(peer).to_request()).await;
+            let cookie = res.response().cookies().next().unwrap().into_owned();
+            // known sessions aren't limited
+            let req = test::TestRequest::get().peer_addr(peer).cookie(cookie).to_request();
+            assert_eq!(test::read_body(test::call_service(&mut app, req).await).await, "2");
+            let res = test::call_service(&mut app, test::TestRequest::get().peer_addr(peer).to_request()).await;
+            assert_eq!(res.status().as_u16(), status);
+            assert!(res.response().cookies().next().is_none());
+        }
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn sessions_are_saved_under_a_keyed_hash() {
+        let store = Arc::new(ServerSessionState::new());
+        let session = ServerSession::signed(&[0; 32]).store(store.clone()).id_prefix("dev_").id_generator(|| "raw".to_owned());
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        assert!(cookie.as_ref().unwrap().value().ends_with("dev_raw"));
+        let ids = store.ids().await.unwrap();
+        assert_eq!(ids.len(), 1);
+        assert!(ids[0].starts_with("dev_") && ids[0] != "dev_raw");
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "2");
+    }
+
+    #[actix_rt::test]
+    async fn retired_key_sessions_move_to_the_current_key() {
+        let store = Arc::new(ServerSessionState::new());
+        let old = ServerSession::signed(&[1; 32]).store(store.clone());
+        let mut app = test::init_service(App::new().wrap(old).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        let old_ids = store.ids().await.unwrap();
+
+        let new = ServerSession::signed(&[2; 32]).retired_keys(vec![[1; 32]]).store(store.clone());
+        let mut app = test::init_service(App::new().wrap(new).route("/", web::get().to(count))).await;
+        let (reissued, body) = send(&mut app, cookie.as_ref()).await;
+        assert_eq!(body, "2");
+        let new_ids = store.ids().await.unwrap();
+        assert_eq!(new_ids.len(), 1);
+        assert_ne!(new_ids, old_ids);
+        assert_eq!(send(&mut app, reissued.as_ref()).await.1, "3");
+    }
+
+    #[actix_rt::test]
+    async fn renew_rotates_the_session_id() {
+        let store = Arc::new(ServerSessionState::new());
+        let session = ServerSession::signed(&[0; 32]).store(store.clone());
+        let mut app = test::init_service(App::new().wrap(session)
+            .route("/", web::get().to(count))
+            .route("/renew", web::get().to(renew))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        let old_ids = store.ids().await.unwrap();
+        // changes made after renewing are saved under the new id
+        let (renewed, body) = send_to(&mut app, "/renew", cookie.as_ref()).await;
+        assert_eq!(body, "2");
+        assert_ne!(renewed.as_ref().unwrap().value(), cookie.as_ref().unwrap().value());
+        let new_ids = store.ids().await.unwrap();
+        assert_eq!(new_ids.len(), 1);
+        assert_ne!(new_ids, old_ids);
+        assert_eq!(send(&mut app, renewed.as_ref()).await.1, "3");
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "1");
+    }
+
+    #[actix_rt::test]
+    async fn session_limit() {
+        for policy in [SessionLimitPolicy::RejectNew, SessionLimitPolicy::EvictOldest] {
+            let clock = MockClock::default();
+            let session = ServerSession::signed(&[0; 32]).clock(clock.clone()).session_limit(2, policy);
+            let mut app = test::init_service(App::new().wrap(session)
+                .route("/", web::get().to(principal))
+                .route("/login/{name}", web::get().to(login))).await;
+            let mut cookies = Vec::new();
+            for _ in 0..2 {
+                cookies.push(send_to(&mut app, "/login/alice", None).await.0.unwrap());
+                clock.advance(Duration::from_secs(1));
+            }
+            // other principals have their own limit
+            assert!(send_to(&mut app, "/login/bob", None).await.0.is_some());
+            let res = test::call_service(&mut app, request("/login/alice", None)).await;
+            match policy {
+                SessionLimitPolicy::RejectNew => {
+                    assert_eq!(res.status().as_u16(), 409);
+                    assert!(res.response().cookies().next().is_none());
+                    assert_eq!(send(&mut app, Some(&cookies[0])).await.1, "alice");
+                }
+                SessionLimitPolicy::EvictOldest => {
+                    assert_eq!(res.status().as_u16(), 200);
+                    assert!(res.response().cookies().next().is_some());
+                    assert_eq!(send(&mut app, Some(&cookies[0])).await.1, "");
+                }
+            }
+            assert_eq!(send(&mut app, Some(&cookies[1])).await.1, "alice");
+        }
+    }
+
+    async fn mismatch(session: Session) -> HttpResponse {
+        let flagged = session.client_mismatch() == Some(BindingAction::Flag);
+        let body = count(session).await;
+        if flagged { HttpResponse::Conflict().finish() } else { body }
+    }
+
+    #[actix_rt::test]
+    async fn client_binding() {
+        for action in [BindingAction::Purge, BindingAction::Renew, BindingAction::Flag] {
+            let session = ServerSession::signed(&[0; 32]).client_binding(ClientBinding::new(action).user_agent());
+            let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(mismatch))).await;
+            let send_as = |user_agent: &'static str, cookie: Option<Cookie<'static>>| {
+                let mut req = test::TestRequest::get().header("User-Agent", user_agent);
+                if let Some(cookie) = cookie {
+                    req = req.cookie(cookie);
+                }
+                req.to_request()
+            };
+            let res = test::call_service(&mut app, send_as("browser", None)).await;
+            let cookie = res.response().cookies().next().unwrap().into_owned();
+            let res = test::call_service(&mut app, send_as("browser", Some(cookie.clone()))).await;
+            assert_eq!(test::read_body(res).await, "2");
+
+            let res = test::call_service(&mut app, send_as("curl", Some(cookie.clone()))).await;
+            let reissued = res.response().cookies().next().map(|cookie| cookie.into_owned());
+            match action {
+                BindingAction::Purge => {
+                    assert_ne!(reissued.unwrap().value(), cookie.value());
+                    assert_eq!(test::read_body(res).await, "1");
+                }
+                BindingAction::Renew => {
+                    assert_ne!(reissued.as_ref().unwrap().value(), cookie.value());
+                    assert_eq!(test::read_body(res).await, "3");
+                    // the new client took the session over
+                    let res = test::call_service(&mut app, send_as("curl", reissued)).await;
+                    assert_eq!(test::read_body(res).await, "4");
+                }
+                BindingAction::Flag => {
+                    assert_eq!(res.status().as_u16(), 409);
+                    assert!(reissued.is_none());
+                }
+            }
+            let res = test::call_service(&mut app, send_as("browser", Some(cookie))).await;
+            let expected = if action == BindingAction::Flag { "4" } else { "1" };
+            assert_eq!(test::read_body(res).await, expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn tampered_cookies_start_a_new_session() {
+        for session in [ServerSession::signed(&[0; 32]), ServerSession::private(&[0; 32])] {
+            let store = Arc::new(ServerSessionState::new());
+            let mut app = test::init_service(App::new().wrap(session.store(store.clone())).route("/", web::get().to(count))).await;
+            let (cookie, _) = send(&mut app, None).await;
+            let cookie = cookie.unwrap();
+            assert_eq!(send(&mut app, Some(&cookie)).await.1, "2");
+            let id = store.ids().await.unwrap().remove(0);
+
+            let mut value = cookie.value().to_owned().into_bytes();
+            value[0] = if value[0] == b'A' { b'B' } else { b'A' };
+            let tampered = Cookie::new(cookie.name().to_owned(), String::from_utf8(value).unwrap());
+            let forged = Cookie::new(cookie.name().to_owned(), id);
+            for cookie in [tampered, forged] {
+                let (reissued, body) = send(&mut app, Some(&cookie)).await;
+                assert_eq!(body, "1");
+                assert!(reissued.is_some());
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn private_cookies_hide_the_session_id() {
+        let session = ServerSession::private(&[0; 32]).id_generator(|| "session-id".to_owned());
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        assert!(!cookie.as_ref().unwrap().value().contains("session-id"));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "2");
+    }
+
+    #[actix_rt::test]
+    async fn retired_keys_verify_cookies_until_reissued() {
+        let store = Arc::new(ServerSessionState::new());
+        let app_with = |session: ServerSession| test::init_service(App::new()
+            .wrap(session.store(store.clone()))
+            .route("/", web::get().to(count)));
+        let mut old = app_with(ServerSession::private(&[1; 32])).await;
+        let (cookie, _) = send(&mut old, None).await;
+
+        let mut current = app_with(ServerSession::private(&[2; 32])).await;
+        let mut rotated = app_with(ServerSession::private(&[2; 32]).retired_keys(vec![[1; 32]])).await;
+        let (reissued, body) = send(&mut rotated, cookie.as_ref()).await;
+        assert_eq!(body, "2");
+        assert_ne!(reissued.as_ref().unwrap().value(), cookie.as_ref().unwrap().value());
+        // the reissued cookie no longer needs the retired key
+        assert_eq!(send(&mut current, reissued.as_ref()).await.1, "3");
+        assert_eq!(send(&mut current, cookie.as_ref()).await.1, "1");
+    }
+
+    #[actix_rt::test]
+    async fn generated_ids_skip_saved_sessions() {
+        let ids = std::sync::Mutex::new(vec!["b", "a", "a", "a"]);
+        let session = ServerSession::signed(&[0; 32]).id_generator(move || ids.lock().unwrap().pop().unwrap_or("a").to_owned());
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (first, _) = send(&mut app, None).await;
+        assert!(first.unwrap().value().ends_with("a"));
+        let (second, _) = send(&mut app, None).await;
+        assert!(second.unwrap().value().ends_with("b"));
+        // every later id is taken
+        let err = app.call(request("/", None)).await.err().unwrap();
+        assert_eq!(err.to_string(), "No unused session id after 8 attempts");
+    }
+
+    #[actix_rt::test]
+    async fn hooks_follow_the_session_lifecycle() {
+        let clock = MockClock::default();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let events = events.clone();
+            move |id: &str, _: &State| events.lock().unwrap().push((name, id.to_owned()))
+        };
+        let session = ServerSession::signed(&[0; 32])
+            .clock(clock.clone())
+            .set_timeout(1)
+            .on_create(record("create"))
+            .on_renew(record("renew"))
+            .on_purge(record("purge"))
+            .on_expire(record("expire"));
+        let mut app = test::init_service(App::new().wrap(session)
+            .route("/", web::get().to(count))
+            .route("/renew", web::get().to(renew))
+            .route("/purge", web::get().to(purge))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        send(&mut app, cookie.as_ref()).await;
+        let (cookie, _) = send_to(&mut app, "/renew", cookie.as_ref()).await;
+        send_to(&mut app, "/purge", cookie.as_ref()).await;
+        let (cookie, _) = send(&mut app, None).await;
+        clock.advance(Duration::from_secs(120));
+        send(&mut app, cookie.as_ref()).await;
+
+        let events = events.lock().unwrap();
+        let names: Vec<_> = events.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["create", "renew", "purge", "create", "expire", "create"]);
+        assert_ne!(events[0].1, events[1].1);
+        assert_eq!(events[1].1, events[2].1);
+        assert_eq!(events[3].1, events[4].1);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_and_cookies_expire_by_the_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let session = ServerSession::signed(&[0; 32]).clock(clock.clone()).set_timeout(10).expires_in(3600);
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        let expires = cookie.as_ref().unwrap().expires().unwrap();
+        assert_eq!(expires.unix_timestamp(), 1_600_003_600);
+        clock.advance(Duration::from_secs(9 * 60));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "2");
+        clock.advance(Duration::from_secs(9 * 60));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "3");
+        clock.advance(Duration::from_secs(11 * 60));
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "1");
+    }
+
+    #[actix_rt::test]
+    async fn middleware_instances_keep_their_own_sessions() {
+        let clock = MockClock::default();
+        let admin = ServerSession::signed(&[0; 32]).name("admin").clock(clock.clone()).set_timeout(1);
+        let public = ServerSession::signed(&[0; 32]).name("public").clock(clock.clone()).set_timeout(30);
+        let mut app = test::init_service(App::new()
+            .service(web::scope("/admin").wrap(admin).route("/", web::get().to(count)))
+            .service(web::scope("/public").wrap(public).route("/", web::get().to(count)))).await;
+        let (admin, _) = send_to(&mut app, "/admin/", None).await;
+        let (public, _) = send_to(&mut app, "/public/", None).await;
+        assert_eq!(send_to(&mut app, "/admin/", admin.as_ref()).await.1, "2");
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(send_to(&mut app, "/admin/", admin.as_ref()).await.1, "1");
+        assert_eq!(send_to(&mut app, "/public/", public.as_ref()).await.1, "2");
+    }
+
+    #[actix_rt::test]
+    async fn capacity_applies_to_the_default_store() {
+        let evicted = Arc::new(std::sync::Mutex::new(0));
+        let hook = evicted.clone();
+        let session = ServerSession::signed(&[0; 32])
+            .capacity(1, EvictionPolicy::Oldest)
+            .clock(MockClock::default())
+            .on_expire(move |_, _| *hook.lock().unwrap() += 1);
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (first, _) = send(&mut app, None).await;
+        let (second, _) = send(&mut app, None).await;
+        assert_eq!(*evicted.lock().unwrap(), 1);
+        assert_eq!(send(&mut app, second.as_ref()).await.1, "2");
+        assert_eq!(send(&mut app, first.as_ref()).await.1, "1");
+    }
+}
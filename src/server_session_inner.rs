@@ -1,35 +1,90 @@
-use actix_web::{Error, HttpMessage, ResponseError};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use actix_web::{Error, HttpMessage};
 use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
 use actix_web::http::header::SET_COOKIE;
 use actix_web::http::HeaderValue;
-use derive_more::{Display, From};
-use rand::Rng;
-use serde_json::error::Error as JsonError;
+use derive_more::Display;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
 use time::{Duration, OffsetDateTime};
 
-/// Errors that can occur during handling cookie session
-#[derive(Debug, From, Display)]
-pub enum CookieSessionError {
-    /// Size of the serialized session is greater than 4000 bytes.
-    #[display(fmt = "Size of the serialized session is greater than 4000 bytes.")]
-    Overflow,
-    /// Fail to serialize session.
-    #[display(fmt = "Fail to serialize session")]
-    Serialize(JsonError),
-}
-
-impl ResponseError for CookieSessionError {}
+use crate::client_binding::ClientBinding;
+use crate::clock::{Clock, SystemClock};
+use crate::id_generator::{IdGenerator, IdGeneratorError, RandomIdGenerator};
+use crate::session_codec::SessionCodec;
+use crate::rate_limit::CreationLimiter;
+use crate::server_session_state::{EvictionPolicy, ServerSessionState, State};
+use crate::session_hooks::{call_hooks, SessionHooks};
+use crate::session_limit::SessionLimitPolicy;
+use crate::session_store::SessionStore;
 
 pub enum CookieSecurity {
     Signed,
     Private,
 }
 
+/// Cookie name prefixes that make browsers enforce the cookie attributes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CookiePrefix {
+    /// `__Host-`: secure, no domain and path `/`, so the cookie is only sent
+    /// to the host that set it.
+    Host,
+    /// `__Secure-`: secure.
+    Secure,
+}
+
+/// Errors returned by `ServerSession::validate` for cookie attributes breaking
+/// the rules of the prefix of the cookie name
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum CookiePrefixError {
+    /// The cookie isn't secure.
+    #[display(fmt = "{} session cookies must be secure", "_0.as_str()")]
+    Insecure(CookiePrefix),
+    /// A `__Host-` cookie has a domain.
+    #[display(fmt = "__Host- session cookies must not have a domain")]
+    Domain,
+    /// A `__Host-` cookie has another path than `/`.
+    #[display(fmt = "__Host- session cookies must have the path /")]
+    Path,
+}
+
+impl std::error::Error for CookiePrefixError {}
+
+impl CookiePrefix {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CookiePrefix::Host => "__Host-",
+            CookiePrefix::Secure => "__Secure-",
+        }
+    }
+}
+
 pub struct ServerSessionInner {
     pub(crate) name: String,
+    pub(crate) prefix: Option<CookiePrefix>,
     pub(crate) path: String,
     key: Key,
+    /// Keys of cookies that are still accepted, then re-issued with `key`.
+    pub(crate) retired_keys: Vec<Key>,
+    security: CookieSecurity,
+    pub(crate) store: Arc<dyn SessionStore>,
+    /// Whether `store` is the in-memory store created with the middleware.
+    pub(crate) default_store: bool,
+    /// Capacity of the default store.
+    pub(crate) capacity: Option<(usize, EvictionPolicy)>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) id_generator: Arc<dyn IdGenerator>,
+    pub(crate) id_prefix: String,
+    pub(crate) timeout: StdDuration,
+    pub(crate) max_lifetime: Option<StdDuration>,
+    pub(crate) codec: SessionCodec,
+    pub(crate) hooks: SessionHooks,
+    pub(crate) binding: Option<ClientBinding>,
+    pub(crate) session_limit: Option<(usize, SessionLimitPolicy)>,
+    pub(crate) rate_limit: Option<CreationLimiter>,
     pub(crate) secure: bool,
     pub(crate) http_only: bool,
     pub(crate) lazy: bool,
@@ -40,11 +95,27 @@ pub struct ServerSessionInner {
 }
 
 impl ServerSessionInner {
-    pub fn new(key: &[u8]) -> Self {
+    pub fn new(key: &[u8], security: CookieSecurity, store: Arc<dyn SessionStore>) -> Self {
         ServerSessionInner {
             name: "actix-session".to_owned(),
+            prefix: None,
             path: "/".to_owned(),
             key: Key::derive_from(key),
+            retired_keys: Vec::new(),
+            security,
+            store,
+            default_store: true,
+            capacity: None,
+            clock: Arc::new(SystemClock),
+            id_generator: Arc::new(RandomIdGenerator),
+            id_prefix: String::new(),
+            timeout: StdDuration::from_secs(30 * 60),
+            max_lifetime: None,
+            codec: SessionCodec::default(),
+            hooks: SessionHooks::default(),
+            binding: None,
+            session_limit: None,
+            rate_limit: None,
             lazy: false,
             secure: false,
             http_only: true,
@@ -55,31 +126,163 @@ impl ServerSessionInner {
         }
     }
 
-    pub fn get_session_id(&self, req: &ServiceRequest) -> (bool, String) {
+    /// Checks the cookie attributes against the rules of the prefix of its
+    /// name, browsers would silently drop the cookie otherwise.
+    pub(crate) fn check_prefix(&self) -> Result<(), CookiePrefixError> {
+        let prefix = if self.name.starts_with(CookiePrefix::Host.as_str()) {
+            CookiePrefix::Host
+        } else if self.name.starts_with(CookiePrefix::Secure.as_str()) {
+            CookiePrefix::Secure
+        } else {
+            return Ok(());
+        };
+        if !self.secure {
+            return Err(CookiePrefixError::Insecure(prefix));
+        }
+        if prefix == CookiePrefix::Host {
+            if self.domain.is_some() {
+                return Err(CookiePrefixError::Domain);
+            }
+            if self.path != "/" {
+                return Err(CookiePrefixError::Path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the default store by one with the current clock and capacity.
+    pub(crate) fn reset_default_store(&mut self) {
+        let mut store = ServerSessionState::new().shared_clock(self.clock.clone());
+        if let Some((max, policy)) = self.capacity {
+            store = store.capacity(max, policy);
+        }
+        store.start();
+        self.set_store(Arc::new(store));
+    }
+
+    /// Replaces the store, registering the expiry hooks and the maximum
+    /// lifetime on the new one.
+    pub(crate) fn set_store(&mut self, store: Arc<dyn SessionStore>) {
+        for hook in &self.hooks.on_expire {
+            store.on_expire(hook.clone());
+        }
+        store.set_max_lifetime(self.max_lifetime);
+        self.store = store;
+    }
+
+    /// Returns the session id of the request, or `None` when the request has
+    /// no session cookie or its cookie can't be verified with any key.
+    ///
+    /// The flag is set when the cookie was verified with a retired key.
+    pub fn get_session_id(&self, req: &ServiceRequest) -> Option<(String, bool)> {
         if let Ok(cookies) = req.cookies() {
             for cookie in cookies.iter() {
                 if cookie.name() == self.name {
-                    if let val = cookie.value() {
-                        let key = val.to_string();
-                        return (false, key);
+                    if let Some(id) = self.verify(&self.key, cookie) {
+                        return Some((id, false));
+                    }
+                    for key in &self.retired_keys {
+                        if let Some(id) = self.verify(key, cookie) {
+                            return Some((id, true));
+                        }
                     }
                 }
             }
         }
-        let id = self.generate_id();
-        (true, id)
+        None
+    }
+
+    fn verify(&self, key: &Key, cookie: &Cookie<'static>) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie.clone());
+        let verified = match self.security {
+            CookieSecurity::Signed => jar.signed(key).get(&self.name),
+            CookieSecurity::Private => jar.private(key).get(&self.name),
+        };
+        verified.map(|cookie| cookie.value().to_string())
+    }
+
+    /// Returns the fingerprint of the client sending `req` when sessions are
+    /// bound to their client.
+    pub(crate) fn fingerprint(&self, req: &ServiceRequest) -> Option<String> {
+        self.binding.as_ref().map(|binding| binding.fingerprint(&self.key, req))
+    }
+
+    /// Checks whether `fingerprint` is the one of the client sending `req`
+    /// computed with a retired key.
+    pub(crate) fn retired_fingerprint(&self, req: &ServiceRequest, fingerprint: &str) -> bool {
+        match self.binding {
+            Some(ref binding) => self.retired_keys.iter().any(|key| binding.fingerprint(key, req) == fingerprint),
+            None => false,
+        }
+    }
+
+    /// Returns the id the session `id` is saved under in the store.
+    ///
+    /// It is a keyed hash of the id, so the contents of the store can't be
+    /// replayed as session cookies. The id prefix is kept.
+    pub fn store_id(&self, id: &str) -> String {
+        self.hash_id(&self.key, id)
+    }
+
+    /// Returns the ids the session `id` was saved under with the retired keys.
+    pub(crate) fn retired_store_ids<'a>(&'a self, id: &'a str) -> impl Iterator<Item=String> + 'a {
+        self.retired_keys.iter().map(move |key| self.hash_id(key, id))
+    }
+
+    fn hash_id(&self, key: &Key, id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(key.signing()).expect("HMAC accepts any key length");
+        mac.update(b"session id\0");
+        mac.update(id.as_bytes());
+        let hash = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        format!("{}{}", self.id_prefix, hash)
+    }
+
+    /// Returns a new prefixed id that no saved session uses.
+    pub async fn generate_id(&self) -> Result<String, Error> {
+        const ATTEMPTS: usize = 8;
+        for _ in 0..ATTEMPTS {
+            let id = format!("{}{}", self.id_prefix, self.id_generator.generate());
+            if !self.store.exists(&self.store_id(&id)).await? {
+                return Ok(id);
+            }
+        }
+        Err(IdGeneratorError::Exhausted(ATTEMPTS).into())
     }
 
-    pub fn generate_id(&self) -> String {
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
-        let mut rng = rand::thread_rng();
-        let id: String = (0..32)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect();
-        id
+    /// Makes room for the session saved under `id` of `principal` under the session limit,
+    /// returning `false` when the login must be rejected.
+    pub(crate) async fn admit(&self, id: &str, principal: &str) -> Result<bool, Error> {
+        let (max, policy) = match self.session_limit {
+            Some(limit) => limit,
+            None => return Ok(true),
+        };
+        let now = self.clock.now();
+        let mut others: Vec<(String, State)> = Vec::new();
+        for other in self.store.sessions_of(principal).await? {
+            if other == id {
+                continue;
+            }
+            match self.store.load(&other).await? {
+                Some(state) if !state.is_expired(now) && state.principal() == Some(principal) => {
+                    others.push((other, state))
+                }
+                _ => {}
+            }
+        }
+        if others.len() < max {
+            return Ok(true);
+        }
+        if policy == SessionLimitPolicy::RejectNew {
+            return Ok(false);
+        }
+        others.sort_by_key(|(_, state)| state.created_at());
+        let evicted = others.len() + 1 - max;
+        for (other, state) in others.drain(..evicted) {
+            self.store.delete(&other).await?;
+            call_hooks(&self.hooks.on_purge, &other, &state);
+        }
+        Ok(true)
     }
 
     pub fn set_cookie<B>(&self, res: &mut ServiceResponse<B>, value: String) -> Result<(), Error> {
@@ -87,44 +290,58 @@ impl ServerSessionInner {
             return Ok(());
         }
 
-        let mut cookie = Cookie::new(self.name.clone(), value);
-        cookie.set_path(self.path.clone());
-        cookie.set_secure(self.secure);
-        cookie.set_http_only(self.http_only);
-
-        if let Some(ref domain) = self.domain {
-            cookie.set_domain(domain.clone());
-        }
+        let mut cookie = self.cookie(value);
 
         if let Some(expires_in) = self.expires_in {
-            cookie.set_expires(OffsetDateTime::now_utc() + expires_in);
+            cookie.set_expires(OffsetDateTime::from(self.clock.now()) + expires_in);
         }
 
         if let Some(max_age) = self.max_age {
             cookie.set_max_age(max_age);
         }
 
-        if let Some(same_site) = self.same_site {
-            cookie.set_same_site(same_site);
+        let mut jar = CookieJar::new();
+        match self.security {
+            CookieSecurity::Signed => jar.signed(&self.key).add(cookie),
+            CookieSecurity::Private => jar.private(&self.key).add(cookie),
         }
 
-        let val = HeaderValue::from_str(&cookie.to_string())?;
-        res.headers_mut().append(SET_COOKIE, val);
+        for cookie in jar.delta() {
+            let val = HeaderValue::from_str(&cookie.encoded().to_string())?;
+            res.headers_mut().append(SET_COOKIE, val);
+        }
 
         Ok(())
     }
 
     /// invalidates session cookie
     pub fn remove_cookie<B>(&self, res: &mut ServiceResponse<B>) -> Result<(), Error> {
-        let mut cookie = Cookie::named(self.name.clone());
-        cookie.set_path(self.path.clone());
-        cookie.set_value("");
+        let mut cookie = self.cookie(String::new());
         cookie.set_max_age(Duration::zero());
-        cookie.set_expires(OffsetDateTime::now_utc() - Duration::days(365));
+        cookie.set_expires(OffsetDateTime::from(self.clock.now()) - Duration::days(365));
 
         let val = HeaderValue::from_str(&cookie.to_string())?;
         res.headers_mut().append(SET_COOKIE, val);
 
         Ok(())
     }
+
+    /// Session cookie holding `value`, with the attributes shared by the
+    /// cookies setting and removing it so browsers match them.
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(self.name.clone(), value);
+        cookie.set_path(self.path.clone());
+        cookie.set_secure(self.secure);
+        cookie.set_http_only(self.http_only);
+
+        if let Some(ref domain) = self.domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        if let Some(same_site) = self.same_site {
+            cookie.set_same_site(same_site);
+        }
+
+        cookie
+    }
 }
\ No newline at end of file
@@ -1,22 +1,128 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::thread;
+use std::collections::hash_map::RandomState;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use std::time::SystemTime;
 
 use actix_web::Error;
-use serde;
+use futures_util::future::{FutureExt, LocalBoxFuture, ready};
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-use serde_millis;
 
-#[derive(Serialize, Deserialize)]
+use crate::clock::{Clock, SystemClock};
+use crate::expiry_worker::ExpiryWorker;
+use crate::session_codec::{SessionCodec, SessionCodecError};
+use crate::session_hooks::{call_hooks, SessionHook};
+use crate::session_store::{LifetimeCap, SessionStore};
+
+/// (De)serializes session values as strings for human readable formats, so JSON
+/// states stay readable, and as raw bytes for the binary ones.
+mod values {
+    use std::collections::HashMap;
+    use std::fmt;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::ser::{Error, SerializeMap};
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct ByteBufVisitor;
+
+            impl<'de> Visitor<'de> for ByteBufVisitor {
+                type Value = ByteBuf;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("bytes")
+                }
+
+                fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+                    Ok(ByteBuf(v.to_vec()))
+                }
+
+                fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ByteBuf, E> {
+                    Ok(ByteBuf(v))
+                }
+
+                fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+                    let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                    while let Some(b) = seq.next_element()? {
+                        bytes.push(b);
+                    }
+                    Ok(ByteBuf(bytes))
+                }
+            }
+
+            deserializer.deserialize_byte_buf(ByteBufVisitor)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(value: &HashMap<String, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        let readable = serializer.is_human_readable();
+        let mut map = serializer.serialize_map(Some(value.len()))?;
+        for (k, v) in value {
+            if readable {
+                map.serialize_entry(k, std::str::from_utf8(v).map_err(S::Error::custom)?)?;
+            } else {
+                map.serialize_entry(k, &Bytes(v))?;
+            }
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<u8>>, D::Error> {
+        if deserializer.is_human_readable() {
+            let value = HashMap::<String, String>::deserialize(deserializer)?;
+            Ok(value.into_iter().map(|(k, v)| (k, v.into_bytes())).collect())
+        } else {
+            let value = HashMap::<String, ByteBuf>::deserialize(deserializer)?;
+            Ok(value.into_iter().map(|(k, v)| (k, v.0)).collect())
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
-    value: HashMap<String, String>,
+    #[serde(with = "values")]
+    value: HashMap<String, Vec<u8>>,
     #[serde(with = "serde_millis")]
     timeout: Duration,
     #[serde(with = "serde_millis")]
     last_use_time: SystemTime,
+    /// States saved before it was recorded count as created at the epoch, so
+    /// a maximum lifetime ends them.
+    #[serde(with = "serde_millis", default = "unknown_creation")]
+    created_at: SystemTime,
+    #[serde(with = "serde_millis", default)]
+    max_lifetime: Option<Duration>,
+    /// Fingerprint of the client the session is bound to.
+    #[serde(default)]
+    client: Option<String>,
+    /// Authenticated principal owning the session.
+    #[serde(default)]
+    principal: Option<String>,
+    /// Secret the CSRF tokens of the session are derived from.
+    #[serde(default)]
+    csrf_secret: Option<String>,
+    #[serde(skip)]
+    codec: SessionCodec,
+}
+
+fn unknown_creation() -> SystemTime {
+    SystemTime::UNIX_EPOCH
 }
 
 impl Default for State {
@@ -27,23 +133,55 @@ impl Default for State {
 
 impl State {
     pub fn new(timeout: Duration) -> Self {
+        let now = SystemTime::now();
         State {
             value: HashMap::new(),
             timeout,
-            last_use_time: SystemTime::now(),
+            last_use_time: now,
+            created_at: now,
+            max_lifetime: None,
+            client: None,
+            principal: None,
+            csrf_secret: None,
+            codec: SessionCodec::default(),
         }
     }
 
+    /// Construct new `State` encoding its values with `codec`.
+    pub fn with_codec(timeout: Duration, codec: SessionCodec) -> Self {
+        State {
+            codec,
+            ..State::new(timeout)
+        }
+    }
+
+    /// Decodes a state written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<State, SessionCodecError> {
+        let (codec, mut state): (SessionCodec, State) = SessionCodec::decode_tagged(bytes)?;
+        state.codec = codec;
+        Ok(state)
+    }
+
+    /// Encodes this state with its codec, for stores that keep bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SessionCodecError> {
+        self.codec.encode_tagged(self)
+    }
+
+    pub fn codec(&self) -> SessionCodec {
+        self.codec
+    }
+
     pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
         if let Some(s) = self.value.get(key) {
-            Ok(Some(serde_json::from_str(s)?))
+            Ok(Some(self.codec.decode(s)?))
         } else {
             Ok(None)
         }
     }
 
-    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) {
-        self.value.insert(key.to_owned(), serde_json::to_string(value).unwrap());
+    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        self.value.insert(key.to_owned(), self.codec.encode(value)?);
+        Ok(())
     }
 
     pub fn remove(&mut self, key: &str) {
@@ -66,74 +204,758 @@ impl State {
         self.timeout
     }
 
-    pub fn update_last_use_time(&mut self) {
-        self.last_use_time = SystemTime::now();
+    /// Sets how long the session may live since its creation, whatever its use.
+    pub fn update_max_lifetime(&mut self, max_lifetime: Option<Duration>) {
+        self.max_lifetime = max_lifetime;
+    }
+
+    /// Lowers the maximum lifetime of the session to `max_lifetime`, a shorter
+    /// one is kept.
+    pub fn cap_lifetime(&mut self, max_lifetime: Option<Duration>) {
+        if let Some(cap) = max_lifetime {
+            self.max_lifetime = Some(self.max_lifetime.map_or(cap, |current| current.min(cap)));
+        }
+    }
+
+    /// Copy of the state with its maximum lifetime lowered to `max_lifetime`,
+    /// see `cap_lifetime`.
+    pub fn capped(&self, max_lifetime: Option<Duration>) -> State {
+        let mut state = self.clone();
+        state.cap_lifetime(max_lifetime);
+        state
+    }
+
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+    }
+
+    /// Fingerprint of the client the session is bound to, see `ClientBinding`.
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    pub(crate) fn bind_client(&mut self, fingerprint: String) {
+        self.client = Some(fingerprint);
+    }
+
+    /// Authenticated principal owning the session, see `Session::set_principal`.
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_deref()
+    }
+
+    pub(crate) fn set_principal(&mut self, principal: String) {
+        self.principal = Some(principal);
+    }
+
+    pub(crate) fn csrf_secret(&self) -> Option<&str> {
+        self.csrf_secret.as_deref()
+    }
+
+    pub(crate) fn set_csrf_secret(&mut self, secret: Option<String>) {
+        self.csrf_secret = secret;
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    pub fn last_use_time(&self) -> SystemTime {
+        self.last_use_time
+    }
+
+    /// Time at which the session expires, through idle timeout or maximum lifetime.
+    pub fn expires_at(&self) -> SystemTime {
+        let idle = self.last_use_time + self.timeout;
+        match self.max_lifetime {
+            Some(max_lifetime) => idle.min(self.created_at + max_lifetime),
+            None => idle,
+        }
+    }
+
+    /// Sets both the creation and the last use time to `now`.
+    pub fn start_at(&mut self, now: SystemTime) {
+        self.created_at = now;
+        self.last_use_time = now;
+    }
+
+    pub fn update_last_use_time(&mut self, now: SystemTime) {
+        self.last_use_time = now;
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now > self.expires_at()
+    }
+}
+
+/// One shard of the in-memory store.
+///
+/// `deadlines` orders session ids by expiry time. Entries are pushed on every
+/// save or touch and never updated in place, so an entry is only acted upon
+/// when it still matches the expiry time of the stored state.
+///
+/// `principals` indexes the sessions of the shard by principal.
+///
+/// `eviction` orders the sessions by the time the eviction policy of the
+/// store ranks them by, when the store has a capacity.
+#[derive(Default)]
+struct Segment {
+    states: HashMap<String, State>,
+    deadlines: BinaryHeap<Reverse<(SystemTime, String)>>,
+    principals: HashMap<String, HashSet<String>>,
+    eviction: Option<(EvictionPolicy, BTreeSet<(SystemTime, String)>)>,
+}
+
+impl Segment {
+    /// Saves `state` under `id`, returning whether `id` is a new session.
+    fn insert(&mut self, id: &str, state: State) -> bool {
+        let deadline = state.expires_at();
+        if let Some(principal) = state.principal() {
+            self.principals.entry(principal.to_owned()).or_default().insert(id.to_owned());
+        }
+        if let Some((policy, order)) = &mut self.eviction {
+            order.insert((policy.rank(&state), id.to_owned()));
+        }
+        let is_new = match self.states.insert(id.to_string(), state) {
+            Some(previous) => {
+                self.unindex(id, &previous);
+                false
+            }
+            None => true,
+        };
+        // scheduled once stored, a compaction rebuilds the heap from `states`
+        self.schedule(id, deadline);
+        is_new
+    }
+
+    /// Marks session `id` as used at `now`.
+    fn touch(&mut self, id: &str, now: SystemTime) {
+        let state = match self.states.get_mut(id) {
+            Some(state) => state,
+            None => return,
+        };
+        if let Some((policy, order)) = &mut self.eviction {
+            order.remove(&(policy.rank(state), id.to_owned()));
+            state.update_last_use_time(now);
+            order.insert((policy.rank(state), id.to_owned()));
+        } else {
+            state.update_last_use_time(now);
+        }
+        let deadline = state.expires_at();
+        self.schedule(id, deadline);
+    }
+
+    /// The session the eviction policy drops first, other than `id`.
+    fn first_evicted(&self, id: &str) -> Option<(SystemTime, String)> {
+        let (_, order) = self.eviction.as_ref()?;
+        order.iter().find(|(_, other)| other != id).cloned()
+    }
+
+    fn remove(&mut self, id: &str) -> Option<State> {
+        let state = self.states.remove(id)?;
+        self.unindex(id, &state);
+        Some(state)
+    }
+
+    /// Removes `id` from the indexes of `state`, unless the stored state of
+    /// `id` still has the same principal or eviction rank.
+    fn unindex(&mut self, id: &str, state: &State) {
+        if let Some((policy, order)) = &mut self.eviction {
+            let rank = policy.rank(state);
+            if !matches!(self.states.get(id), Some(current) if policy.rank(current) == rank) {
+                order.remove(&(rank, id.to_owned()));
+            }
+        }
+        let principal = match state.principal() {
+            Some(principal) => principal,
+            None => return,
+        };
+        if matches!(self.states.get(id), Some(current) if current.principal() == Some(principal)) {
+            return;
+        }
+        if let Some(ids) = self.principals.get_mut(principal) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.principals.remove(principal);
+            }
+        }
+    }
+
+    /// Applies `max_lifetime` to every session of the segment.
+    fn cap_lifetimes(&mut self, max_lifetime: Option<Duration>) {
+        for state in self.states.values_mut() {
+            state.cap_lifetime(max_lifetime);
+        }
+        self.deadlines = self.states.iter()
+            .map(|(id, state)| Reverse((state.expires_at(), id.clone())))
+            .collect();
+    }
+
+    fn schedule(&mut self, id: &str, deadline: SystemTime) {
+        self.deadlines.push(Reverse((deadline, id.to_string())));
+        // drop outdated entries once they outnumber live sessions
+        if self.deadlines.len() > 2 * self.states.len() + 64 {
+            self.deadlines = self.states.iter()
+                .map(|(id, state)| Reverse((state.expires_at(), id.clone())))
+                .collect();
+        }
+    }
+
+    /// Moves up to `batch` sessions expired at `now` into `expired`, returning
+    /// how many deadlines were processed.
+    fn expire(&mut self, now: SystemTime, batch: usize, expired: &mut Vec<(String, State)>) -> usize {
+        let mut processed = 0;
+        while processed < batch {
+            match self.deadlines.peek() {
+                Some(Reverse((deadline, _))) if *deadline < now => {}
+                _ => break,
+            }
+            let Reverse((deadline, id)) = self.deadlines.pop().unwrap();
+            if matches!(self.states.get(&id), Some(state) if state.expires_at() == deadline) {
+                let state = self.remove(&id).unwrap();
+                expired.push((id, state));
+            }
+            processed += 1;
+        }
+        processed
     }
+}
+
+type Shard = RwLock<Segment>;
 
-    pub fn is_expired(&self) -> bool {
-        SystemTime::now() > self.last_use_time + self.timeout
+/// Which session `ServerSessionState` drops to make room for a new one once
+/// it holds as many sessions as its capacity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Drop the session used the longest time ago.
+    LeastRecentlyUsed,
+    /// Drop the session created first.
+    Oldest,
+}
+
+impl EvictionPolicy {
+    /// Time ranking `state` for eviction, the earliest is dropped first.
+    fn rank(&self, state: &State) -> SystemTime {
+        match self {
+            EvictionPolicy::LeastRecentlyUsed => state.last_use_time(),
+            EvictionPolicy::Oldest => state.created_at(),
+        }
     }
 }
 
+/// Removes expired sessions from every shard, holding a shard lock for at
+/// most `batch` sessions at a time.
+fn sweep(shards: &[Shard], len: &AtomicUsize, batch: usize, hooks: &RwLock<Vec<SessionHook>>, now: SystemTime) {
+    let mut expired = Vec::new();
+    for shard in shards {
+        loop {
+            let processed = shard.write().unwrap().expire(now, batch, &mut expired);
+            len.fetch_sub(expired.len(), Ordering::SeqCst);
+            for (id, state) in expired.drain(..) {
+                call_hooks(&hooks.read().unwrap(), &id, &state);
+            }
+            if processed < batch {
+                break;
+            }
+        }
+    }
+}
+
+/// In-memory session store.
+///
+/// Sessions are spread over hash-sharded segments, each behind its own lock,
+/// so requests on different sessions don't wait on each other. States are kept
+/// as is, without being serialized, and expire in deadline order so a sweep
+/// only touches sessions that actually expired.
 pub struct ServerSessionState {
-    state: Arc<RwLock<HashMap<String, String>>>,
-    timeout: Duration,
-    started: bool,
+    shards: Arc<Vec<Shard>>,
+    /// Number of sessions in all shards.
+    len: Arc<AtomicUsize>,
+    hasher: RandomState,
+    sweep_interval: Duration,
+    sweep_batch: usize,
+    expire_hooks: Arc<RwLock<Vec<SessionHook>>>,
+    /// Sessions the store may hold, and which to drop beyond.
+    capacity: Option<(usize, EvictionPolicy)>,
+    max_lifetime: LifetimeCap,
+    clock: Arc<dyn Clock>,
+    worker: Mutex<Option<ExpiryWorker>>,
+}
+
+impl Default for ServerSessionState {
+    fn default() -> Self {
+        ServerSessionState::new()
+    }
 }
 
 impl ServerSessionState {
     pub fn new() -> Self {
+        ServerSessionState::with_shards(16)
+    }
+
+    /// Construct new `ServerSessionState` split into `count` shards.
+    ///
+    /// Panics if `count` is zero.
+    pub fn with_shards(count: usize) -> Self {
+        assert!(count > 0, "ServerSessionState needs at least one shard");
         ServerSessionState {
-            state: Arc::new(RwLock::new(HashMap::new())),
-            started: false,
-            timeout: Duration::from_secs(30 * 60),
+            shards: Arc::new((0..count).map(|_| RwLock::new(Segment::default())).collect()),
+            len: Arc::new(AtomicUsize::new(0)),
+            hasher: RandomState::new(),
+            sweep_interval: Duration::from_secs(1),
+            sweep_batch: 1000,
+            expire_hooks: Arc::new(RwLock::new(Vec::new())),
+            capacity: None,
+            max_lifetime: LifetimeCap::default(),
+            clock: Arc::new(SystemClock),
+            worker: Mutex::new(None),
         }
     }
 
-    pub fn start(&mut self) {
-        if self.started {
-            return;
+    /// Sets how often expired sessions are removed. Default is one second.
+    pub fn sweep_interval(mut self, value: Duration) -> Self {
+        self.sweep_interval = value;
+        self
+    }
+
+    /// Sets how many sessions a sweep removes from a shard before releasing
+    /// its lock. Default is 1000.
+    ///
+    /// Panics if `value` is zero.
+    pub fn sweep_batch(mut self, value: usize) -> Self {
+        assert!(value > 0, "sweep batch must not be zero");
+        self.sweep_batch = value;
+        self
+    }
+
+    /// Sets the clock deciding when sessions expire. Default is `SystemClock`.
+    pub fn clock<C: Clock + 'static>(self, clock: C) -> Self {
+        self.shared_clock(Arc::new(clock))
+    }
+
+    pub(crate) fn shared_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Caps the number of live sessions, dropping one chosen by `policy` to
+    /// make room for a new one. Default is no cap.
+    ///
+    /// Sessions are kept ordered by `policy`, so the one to drop is found
+    /// without scanning the store. Dropped sessions are passed to the
+    /// `on_expire` hooks. Panics if `max` is zero.
+    ///
+    /// `ServerSession::capacity` sets it on the default store. A store passed
+    /// to `ServerSession::store` must be started with `start`, or expired
+    /// sessions only leave it when evicted.
+    pub fn capacity(mut self, max: usize, policy: EvictionPolicy) -> Self {
+        assert!(max > 0, "capacity must not be zero");
+        self.capacity = Some((max, policy));
+        for shard in self.shards.iter() {
+            let mut segment = shard.write().unwrap();
+            let order = segment.states.iter()
+                .map(|(id, state)| (policy.rank(state), id.clone()))
+                .collect();
+            segment.eviction = Some((policy, order));
         }
-        let inner = self.state.clone();
-        thread::spawn(move || {
-            loop {
-                inner.write().unwrap().retain(|_, value| {
-                    let state: State = serde_json::from_str(value).unwrap();
-                    println!("timeout {}", state.timeout.as_secs());
-                    !state.is_expired()
-                });
-                thread::sleep(Duration::from_secs(1));
-            }
+        self
+    }
+
+    /// Number of sessions in the store, expired ones included until they are removed.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes expired sessions.
+    pub fn sweep(&self) {
+        sweep(&self.shards, &self.len, self.sweep_batch, &self.expire_hooks, self.clock.now());
+    }
+
+    /// Starts the thread removing expired sessions.
+    ///
+    /// It runs until `shutdown` is called or the store is dropped.
+    pub fn start(&self) {
+        let len = self.len.clone();
+        let batch = self.sweep_batch;
+        let hooks = self.expire_hooks.clone();
+        let clock = self.clock.clone();
+        ExpiryWorker::start(&self.worker, self.sweep_interval, Arc::downgrade(&self.shards), move |shards| {
+            sweep(shards, &len, batch, &hooks, clock.now());
         });
-        self.started = true;
     }
 
-    pub fn get_state(&self, key: &String) -> Option<State> {
-        if let Some(s) = self.state.clone().read().unwrap().get(key) {
-            match serde_json::from_str(s) {
-                Ok(state) => Some(state),
-                Err(_) => None
+    /// Saves `state` under `id` in `segment`, counting it if it is new.
+    fn insert(&self, segment: &mut Segment, id: &str, state: &State) -> bool {
+        let added = segment.insert(id, self.max_lifetime.apply(state));
+        if added {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+        added
+    }
+
+    fn remove(&self, segment: &mut Segment, id: &str) -> Option<State> {
+        let state = segment.remove(id)?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Some(state)
+    }
+
+    /// Drops the sessions ranked first by the eviction policy, other than
+    /// `id`, while the store holds more sessions than its capacity, then
+    /// passes them to the expiry hooks.
+    fn make_room(&self, id: &str) {
+        let max = match self.capacity {
+            Some((max, _)) => max,
+            None => return,
+        };
+        let mut evicted = Vec::new();
+        while self.len() > max {
+            let victim = self.shards.iter()
+                .filter_map(|shard| shard.read().unwrap().first_evicted(id))
+                .min();
+            let victim = match victim {
+                Some((_, victim)) => victim,
+                None => break,
+            };
+            if let Some(state) = self.remove(&mut self.shard(&victim).write().unwrap(), &victim) {
+                evicted.push((victim, state));
             }
+        }
+        let hooks = self.expire_hooks.read().unwrap();
+        for (id, state) in evicted {
+            call_hooks(&hooks, &id, &state);
+        }
+    }
+
+    fn shard(&self, id: &str) -> &Shard {
+        &self.shards[self.shard_index(id)]
+    }
+
+    fn shard_index(&self, id: &str) -> usize {
+        self.hasher.hash_one(id) as usize % self.shards.len()
+    }
+}
+
+impl SessionStore for ServerSessionState {
+    fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>> {
+        ready(Ok(self.shard(id).read().unwrap().states.get(id).cloned())).boxed_local()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let added = self.insert(&mut self.shard(id).write().unwrap(), id, state);
+        if added {
+            self.make_room(id);
+        }
+        ready(Ok(())).boxed_local()
+    }
+
+    fn touch<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        self.shard(id).write().unwrap().touch(id, self.clock.now());
+        ready(Ok(())).boxed_local()
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        self.remove(&mut self.shard(id).write().unwrap(), id);
+        ready(Ok(())).boxed_local()
+    }
+
+    fn rename<'a>(&'a self, old_id: &'a str, new_id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let (old, new) = (self.shard_index(old_id), self.shard_index(new_id));
+        let added = if old == new {
+            let mut segment = self.shards[old].write().unwrap();
+            self.remove(&mut segment, old_id);
+            self.insert(&mut segment, new_id, state)
         } else {
-            None
+            // lock shards in index order so two renames can't deadlock
+            let (first, second) = (self.shards[old.min(new)].write().unwrap(), self.shards[old.max(new)].write().unwrap());
+            let (mut from, mut to) = if old < new { (first, second) } else { (second, first) };
+            self.remove(&mut from, old_id);
+            self.insert(&mut to, new_id, state)
+        };
+        if added {
+            self.make_room(new_id);
         }
+        ready(Ok(())).boxed_local()
     }
 
-    pub fn new_state(&self) -> State {
-        State::new(self.timeout)
+    fn ids(&self) -> LocalBoxFuture<'_, Result<Vec<String>, Error>> {
+        let mut ids = Vec::new();
+        for shard in self.shards.iter() {
+            ids.extend(shard.read().unwrap().states.keys().cloned());
+        }
+        ready(Ok(ids)).boxed_local()
     }
 
-    pub fn set_state(&mut self, key: &String, state: &State) -> Result<(), Error> {
-        let str = serde_json::to_string(state)?;
-        self.state.write().unwrap().insert(key.to_string(), str);
-        Ok(())
+    fn sessions_of<'a>(&'a self, principal: &'a str) -> LocalBoxFuture<'a, Result<Vec<String>, Error>> {
+        let mut ids = Vec::new();
+        for shard in self.shards.iter() {
+            if let Some(found) = shard.read().unwrap().principals.get(principal) {
+                ids.extend(found.iter().cloned());
+            }
+        }
+        ready(Ok(ids)).boxed_local()
+    }
+
+    fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>> {
+        ready(Ok(self.shard(id).read().unwrap().states.contains_key(id))).boxed_local()
+    }
+
+    fn on_expire(&self, hook: SessionHook) {
+        self.expire_hooks.write().unwrap().push(hook);
+    }
+
+    fn set_max_lifetime(&self, max_lifetime: Option<Duration>) {
+        self.max_lifetime.set(max_lifetime);
+        for shard in self.shards.iter() {
+            shard.write().unwrap().cap_lifetimes(max_lifetime);
+        }
+    }
+
+    fn shutdown(&self) -> LocalBoxFuture<'_, Result<(), Error>> {
+        ExpiryWorker::shutdown(&self.worker)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures_util::future::FutureExt;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    pub(crate) const MINUTE: Duration = Duration::from_secs(60);
+
+    /// New state created at the time of `clock`.
+    pub(crate) fn state_at(clock: &MockClock, timeout: Duration) -> State {
+        let mut state = State::new(timeout);
+        state.start_at(clock.now());
+        state
+    }
+
+    #[test]
+    fn missing_creation_time_is_the_epoch() {
+        let state: State = serde_json::from_str(r#"{"value":{},"timeout":60000,"last_use_time":0}"#).unwrap();
+        assert_eq!(state.created_at(), SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn cap_lifetime_keeps_the_shorter_maximum() {
+        let mut state = State::new(MINUTE);
+        state.cap_lifetime(Some(10 * MINUTE));
+        state.cap_lifetime(Some(20 * MINUTE));
+        assert_eq!(state.max_lifetime(), Some(10 * MINUTE));
+        state.cap_lifetime(None);
+        assert_eq!(state.max_lifetime(), Some(10 * MINUTE));
+    }
+
+    #[test]
+    fn max_lifetime_caps_saved_sessions() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(2).clock(clock.clone());
+        store.save("old", &state_at(&clock, 60 * MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(30 * MINUTE);
+        store.save("new", &state_at(&clock, 60 * MINUTE)).now_or_never().unwrap().unwrap();
+        store.set_max_lifetime(Some(20 * MINUTE));
+        clock.advance(MINUTE);
+        store.sweep();
+        assert!(!store.exists("old").now_or_never().unwrap().unwrap());
+        assert!(store.exists("new").now_or_never().unwrap().unwrap());
+        clock.advance(20 * MINUTE);
+        store.sweep();
+        assert!(!store.exists("new").now_or_never().unwrap().unwrap());
     }
 
-    pub fn remove_state(&mut self, key: &String) {
-        self.state.clone().write().unwrap().remove(key).unwrap();
+    #[test]
+    fn touch_outdates_the_previous_deadline() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(1).clock(clock.clone());
+        store.save("a", &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(MINUTE / 2);
+        store.touch("a").now_or_never().unwrap().unwrap();
+        clock.advance(MINUTE);
+        store.sweep();
+        assert!(store.exists("a").now_or_never().unwrap().unwrap());
+        clock.advance(MINUTE);
+        store.sweep();
+        assert!(!store.exists("a").now_or_never().unwrap().unwrap());
     }
 
-    pub fn set_timeout(&mut self, minutes: u64) {
-        self.timeout = Duration::from_secs(minutes * 60)
+    #[test]
+    fn sweep_expires_every_batch() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(1).clock(clock.clone()).sweep_batch(2);
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let hook = expired.clone();
+        store.on_expire(Arc::new(move |id: &str, _: &State| hook.lock().unwrap().push(id.to_owned())));
+        for i in 0..5 {
+            store.save(&format!("s{}", i), &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+            // outdated deadlines count towards the batch too
+            store.touch(&format!("s{}", i)).now_or_never().unwrap().unwrap();
+        }
+        store.save("live", &state_at(&clock, 10 * MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(2 * MINUTE);
+        store.sweep();
+        let mut expired = expired.lock().unwrap().clone();
+        expired.sort();
+        assert_eq!(expired, (0..5).map(|i| format!("s{}", i)).collect::<Vec<_>>());
+        assert_eq!(store.len(), 1);
+        assert!(store.exists("live").now_or_never().unwrap().unwrap());
     }
-}
\ No newline at end of file
+
+    fn save_at(store: &ServerSessionState, clock: &MockClock, id: &str) {
+        store.save(id, &state_at(clock, 60 * MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(Duration::from_secs(1));
+    }
+
+    fn exists(store: &ServerSessionState, id: &str) -> bool {
+        store.exists(id).now_or_never().unwrap().unwrap()
+    }
+
+    #[test]
+    fn capacity_is_global() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(16).clock(clock.clone()).capacity(64, EvictionPolicy::Oldest);
+        for i in 0..64 {
+            save_at(&store, &clock, &format!("s{}", i));
+        }
+        assert_eq!(store.len(), 64);
+        save_at(&store, &clock, "s64");
+        assert_eq!(store.len(), 64);
+        assert!(!exists(&store, "s0"));
+        assert!((1..=64).all(|i| exists(&store, &format!("s{}", i))));
+    }
+
+    #[test]
+    fn capacity_evicts_least_recently_used() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(4).clock(clock.clone()).capacity(2, EvictionPolicy::LeastRecentlyUsed);
+        save_at(&store, &clock, "a");
+        save_at(&store, &clock, "b");
+        store.touch("a").now_or_never().unwrap().unwrap();
+        clock.advance(Duration::from_secs(1));
+        // saving an existing session makes no room
+        save_at(&store, &clock, "b");
+        assert!(exists(&store, "a") && exists(&store, "b"));
+        save_at(&store, &clock, "c");
+        assert!(exists(&store, "b") && exists(&store, "c"));
+        assert!(!exists(&store, "a"));
+    }
+
+    #[test]
+    fn capacity_passes_evicted_sessions_to_hooks() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(2).clock(clock.clone()).capacity(1, EvictionPolicy::Oldest);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let hook = evicted.clone();
+        store.on_expire(Arc::new(move |id: &str, _: &State| hook.lock().unwrap().push(id.to_owned())));
+        save_at(&store, &clock, "a");
+        save_at(&store, &clock, "b");
+        store.rename("b", "c", &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec!["a".to_owned()]);
+        assert_eq!(store.len(), 1);
+        assert!(exists(&store, "c"));
+    }
+
+    fn sessions_of(store: &ServerSessionState, principal: &str) -> Vec<String> {
+        let mut ids = store.sessions_of(principal).now_or_never().unwrap().unwrap();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn indexes_sessions_by_principal() {
+        let store = ServerSessionState::with_shards(4);
+        for (id, principal) in &[("a", "alice"), ("b", "alice"), ("c", "bob")] {
+            let mut state = State::new(MINUTE);
+            state.set_principal(principal.to_string());
+            store.save(id, &state).now_or_never().unwrap().unwrap();
+        }
+        assert_eq!(sessions_of(&store, "alice"), vec!["a", "b"]);
+        store.delete("a").now_or_never().unwrap().unwrap();
+        assert_eq!(sessions_of(&store, "alice"), vec!["b"]);
+        let mut state = State::new(MINUTE);
+        state.set_principal("bob".to_owned());
+        store.save("b", &state).now_or_never().unwrap().unwrap();
+        assert!(sessions_of(&store, "alice").is_empty());
+        assert_eq!(sessions_of(&store, "bob"), vec!["b", "c"]);
+    }
+
+    #[actix_rt::test]
+    async fn worker_sweeps_until_shutdown() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::new().clock(clock.clone()).sweep_interval(Duration::from_millis(1));
+        store.start();
+        store.save("a", &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(2 * MINUTE);
+        let start = std::time::Instant::now();
+        while exists(&store, "a") {
+            assert!(start.elapsed() < Duration::from_secs(1), "worker didn't sweep");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        store.shutdown().await.unwrap();
+        store.save("b", &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(2 * MINUTE);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(exists(&store, "b"));
+    }
+
+    #[test]
+    fn shards_share_the_load() {
+        let store = Arc::new(ServerSessionState::with_shards(8));
+        let threads: Vec<_> = (0..4).map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    store.save(&format!("{}-{}", t, i), &State::new(MINUTE)).now_or_never().unwrap().unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(store.len(), 400);
+        assert_eq!(store.ids().now_or_never().unwrap().unwrap().len(), 400);
+        assert!(store.shards.iter().all(|shard| !shard.read().unwrap().states.is_empty()));
+    }
+
+    #[test]
+    fn loaded_states_keep_their_values() {
+        let store = ServerSessionState::new();
+        let mut state = State::new(MINUTE);
+        state.set("user", &"alice").unwrap();
+        store.save("a", &state).now_or_never().unwrap().unwrap();
+        state.set("user", &"bob").unwrap();
+        let loaded = store.load("a").now_or_never().unwrap().unwrap().unwrap();
+        assert_eq!(loaded.get::<String>("user").unwrap().as_deref(), Some("alice"));
+        assert_eq!(loaded.get::<String>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn json_states_keep_values_readable() {
+        let mut state = State::new(MINUTE);
+        state.set("user", &"alice").unwrap();
+        let json = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["value"]["user"], "\"alice\"");
+        let decoded: State = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.get::<String>("user").unwrap().as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn compaction_keeps_the_deadline_of_a_new_session() {
+        let clock = MockClock::default();
+        let store = ServerSessionState::with_shards(1).clock(clock.clone());
+        store.save("a", &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+        for _ in 0..65 {
+            store.touch("a").now_or_never().unwrap().unwrap();
+        }
+        store.save("b", &state_at(&clock, MINUTE)).now_or_never().unwrap().unwrap();
+        clock.advance(10 * MINUTE);
+        store.sweep();
+        assert!(!exists(&store, "a"));
+        assert!(!exists(&store, "b"));
+        assert!(store.is_empty());
+    }
+}
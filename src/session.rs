@@ -8,6 +8,8 @@ use futures_util::future::{ok, Ready};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+use crate::client_binding::BindingAction;
+use crate::csrf;
 use crate::server_session_state::State;
 
 pub trait UserSession {
@@ -16,42 +18,50 @@ pub trait UserSession {
 
 impl UserSession for HttpRequest {
     fn get_session(&self) -> Session {
-        Session::get_session(&mut *self.extensions_mut())
+        Session::get_session(&mut self.extensions_mut())
     }
 }
 
 impl UserSession for ServiceRequest {
     fn get_session(&self) -> Session {
-        Session::get_session(&mut *self.extensions_mut())
+        Session::get_session(&mut self.extensions_mut())
     }
 }
 
 impl UserSession for RequestHead {
     fn get_session(&self) -> Session {
-        Session::get_session(&mut *self.extensions_mut())
+        Session::get_session(&mut self.extensions_mut())
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Default)]
 pub enum SessionStatus {
     Changed,
     Purged,
     Renewed,
+    #[default]
     Unchanged,
 }
 
-impl Default for SessionStatus {
-    fn default() -> SessionStatus {
-        SessionStatus::Unchanged
-    }
-}
-
 #[derive(Default)]
 struct SessionInner {
     state: State,
+    /// State as it was when the session got purged.
+    purged: Option<State>,
+    /// Action taken because the session was used by another client.
+    client_mismatch: Option<BindingAction>,
     pub status: SessionStatus,
 }
 
+impl SessionInner {
+    /// Marks the state as changed, a renewed session stays renewed.
+    fn mark_changed(&mut self) {
+        if self.status == SessionStatus::Unchanged {
+            self.status = SessionStatus::Changed;
+        }
+    }
+}
+
 pub struct Session(Rc<RefCell<SessionInner>>);
 
 impl Session {
@@ -64,8 +74,8 @@ impl Session {
     pub fn set<T: Serialize>(&self, key: &str, value: T) -> Result<(), Error> {
         let mut inner = self.0.borrow_mut();
         if inner.status != SessionStatus::Purged {
-            inner.status = SessionStatus::Changed;
-            inner.state.set(key, &value);
+            inner.mark_changed();
+            inner.state.set(key, &value)?;
         }
         Ok(())
     }
@@ -74,7 +84,7 @@ impl Session {
     pub fn remove(&self, key: &str) {
         let mut inner = self.0.borrow_mut();
         if inner.status != SessionStatus::Purged {
-            inner.status = SessionStatus::Changed;
+            inner.mark_changed();
             inner.state.remove(key);
         }
     }
@@ -83,7 +93,7 @@ impl Session {
     pub fn clear(&self) {
         let mut inner = self.0.borrow_mut();
         if inner.status != SessionStatus::Purged {
-            inner.status = SessionStatus::Changed;
+            inner.mark_changed();
             inner.state.clear()
         }
     }
@@ -91,53 +101,98 @@ impl Session {
     /// Removes session, both client and server side.
     pub fn purge(&self) {
         let mut inner = self.0.borrow_mut();
-        inner.status = SessionStatus::Purged;
-        inner.state.clear();
+        if inner.status != SessionStatus::Purged {
+            inner.status = SessionStatus::Purged;
+            let empty = State::with_codec(inner.state.timeout(), inner.state.codec());
+            inner.purged = Some(std::mem::replace(&mut inner.state, empty));
+        }
     }
 
     /// Renews the session key, assigning existing session state to new key.
+    ///
+    /// The old key is no longer valid once the response is sent, call it
+    /// after login to prevent session fixation. CSRF tokens issued before are
+    /// no longer valid either.
     pub fn renew(&self) {
         let mut inner = self.0.borrow_mut();
         if inner.status != SessionStatus::Purged {
             inner.status = SessionStatus::Renewed;
+            inner.state.set_csrf_secret(None);
         }
     }
 
+    /// Sets the authenticated principal of the session, e.g. the user id at login.
+    ///
+    /// Stores index sessions by principal, which `ServerSession::session_limit`
+    /// relies on.
+    pub fn set_principal<S: Into<String>>(&self, principal: S) {
+        let mut inner = self.0.borrow_mut();
+        if inner.status != SessionStatus::Purged {
+            inner.mark_changed();
+            inner.state.set_principal(principal.into());
+        }
+    }
+
+    /// Returns the authenticated principal of the session.
+    pub fn principal(&self) -> Option<String> {
+        self.0.borrow().state.principal().map(ToOwned::to_owned)
+    }
+
+    /// Returns a token for the CSRF protection of `Csrf`, to put in forms or headers.
+    pub fn csrf_token(&self) -> String {
+        let mut inner = self.0.borrow_mut();
+        let secret = match inner.state.csrf_secret() {
+            Some(secret) => secret.to_owned(),
+            None => {
+                let secret = csrf::new_secret();
+                if inner.status != SessionStatus::Purged {
+                    inner.mark_changed();
+                    inner.state.set_csrf_secret(Some(secret.clone()));
+                }
+                secret
+            }
+        };
+        csrf::mask(&secret)
+    }
+
+    pub(crate) fn csrf_secret(&self) -> Option<String> {
+        self.0.borrow().state.csrf_secret().map(ToOwned::to_owned)
+    }
+
+    /// Returns the action taken because the session cookie came from another
+    /// client than the one the session is bound to, see `ClientBinding`.
+    pub fn client_mismatch(&self) -> Option<BindingAction> {
+        self.0.borrow().client_mismatch
+    }
+
     /// Update the session timeout
     pub fn update_timeout(&self, minutes: u64) {
         let mut inner = self.0.borrow_mut();
         if inner.status != SessionStatus::Purged {
-            inner.status = SessionStatus::Changed;
+            inner.mark_changed();
             inner.state.update_timeout(Duration::from_secs(minutes * 60));
         }
     }
 
-    /// Adds the given key-value pairs to the session on the request.
-    ///
-    /// Values that match keys already existing on the session will be overwritten. Values should
-    /// already be JSON serialized.
-    ///
-    /// # Example
+    /// Sets `data` as the session state of the request.
     ///
-    /// ```
-    /// # use actix_session::Session;
-    /// # use actix_web::test;
-    /// #
-    /// let mut req = test::TestRequest::default().to_srv_request();
-    ///
-    /// Session::set_session(
-    ///     vec![("counter".to_string(), serde_json::to_string(&0).unwrap())],
-    ///     &mut req,
-    /// );
-    /// ```
+    /// Values are encoded with the codec of `data`.
     pub(crate) fn set_session(
         data: State,
         req: &mut ServiceRequest,
     ) {
-        let session = Session::get_session(&mut *req.extensions_mut());
-        let mut inner = session.0.borrow_mut();
-        inner.state.update_timeout(data.timeout());
-        inner.state.extend(data);
+        let session = Session::get_session(&mut req.extensions_mut());
+        session.0.borrow_mut().state = data;
+    }
+
+    /// Records the action taken on a client mismatch, `Renew` also renews the
+    /// session like `renew`.
+    pub(crate) fn set_client_mismatch(action: BindingAction, req: &mut ServiceRequest) {
+        let session = Session::get_session(&mut req.extensions_mut());
+        session.0.borrow_mut().client_mismatch = Some(action);
+        if action == BindingAction::Renew {
+            session.renew();
+        }
     }
 
     pub(crate) fn get_changes<B>(
@@ -151,9 +206,11 @@ impl Session {
             .extensions()
             .get::<Rc<RefCell<SessionInner>>>()
         {
-            let timeout = s_impl.borrow().state.timeout().clone();
+            let timeout = s_impl.borrow().state.timeout();
+            let codec = s_impl.borrow().state.codec();
             let state =
-                std::mem::replace(&mut s_impl.borrow_mut().state, State::new(timeout));
+                std::mem::replace(&mut s_impl.borrow_mut().state, State::with_codec(timeout, codec));
+            let state = s_impl.borrow_mut().purged.take().unwrap_or(state);
             (s_impl.borrow().status.clone(), Some(state))
         } else {
             (SessionStatus::Unchanged, None)
@@ -162,7 +219,7 @@ impl Session {
 
     fn get_session(extensions: &mut Extensions) -> Session {
         if let Some(s_impl) = extensions.get::<Rc<RefCell<SessionInner>>>() {
-            return Session(Rc::clone(&s_impl));
+            return Session(Rc::clone(s_impl));
         }
         let inner = Rc::new(RefCell::new(SessionInner::default()));
         extensions.insert(inner.clone());
@@ -177,6 +234,35 @@ impl FromRequest for Session {
 
     #[inline]
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        ok(Session::get_session(&mut *req.extensions_mut()))
+        ok(Session::get_session(&mut req.extensions_mut()))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn renew_on_client_mismatch_drops_the_csrf_secret() {
+        let mut req = TestRequest::default().to_srv_request();
+        let session = req.get_session();
+        session.csrf_token();
+        assert!(session.csrf_secret().is_some());
+        Session::set_client_mismatch(BindingAction::Renew, &mut req);
+        assert_eq!(session.client_mismatch(), Some(BindingAction::Renew));
+        assert!(session.csrf_secret().is_none());
+        assert_eq!(session.0.borrow().status, SessionStatus::Renewed);
+    }
+
+    #[test]
+    fn flag_on_client_mismatch_keeps_the_session() {
+        let mut req = TestRequest::default().to_srv_request();
+        let session = req.get_session();
+        session.csrf_token();
+        Session::set_client_mismatch(BindingAction::Flag, &mut req);
+        assert_eq!(session.client_mismatch(), Some(BindingAction::Flag));
+        assert!(session.csrf_secret().is_some());
+        assert_eq!(session.0.borrow().status, SessionStatus::Changed);
+    }
+}
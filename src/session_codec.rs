@@ -0,0 +1,162 @@
+use actix_web::ResponseError;
+use derive_more::{Display, From};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Errors that can occur while encoding or decoding session data
+#[derive(Debug, From, Display)]
+pub enum SessionCodecError {
+    /// JSON serialization failed.
+    #[display(fmt = "JSON error: {}", _0)]
+    Json(serde_json::Error),
+    /// Bincode serialization failed.
+    #[cfg(feature = "bincode-codec")]
+    #[display(fmt = "Bincode error: {}", _0)]
+    Bincode(bincode::Error),
+    /// MessagePack serialization failed.
+    #[cfg(feature = "msgpack-codec")]
+    #[display(fmt = "MessagePack error: {}", _0)]
+    MessagePackEncode(rmp_serde::encode::Error),
+    /// MessagePack deserialization failed.
+    #[cfg(feature = "msgpack-codec")]
+    #[display(fmt = "MessagePack error: {}", _0)]
+    MessagePackDecode(rmp_serde::decode::Error),
+    /// CBOR serialization failed.
+    #[cfg(feature = "cbor-codec")]
+    #[display(fmt = "CBOR error: {}", _0)]
+    Cbor(serde_cbor::Error),
+    /// Data was encoded with a codec this build doesn't know.
+    #[from(ignore)]
+    #[display(fmt = "Unknown session codec {}", _0)]
+    UnknownCodec(u8),
+}
+
+impl ResponseError for SessionCodecError {}
+
+/// Serialization format of session values.
+///
+/// Stores that save states as bytes encode them with the codec of the state,
+/// prefixed by a tag so a state is always decoded with the codec it was
+/// written with. JSON states are written without tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionCodec {
+    #[default]
+    Json,
+    #[cfg(feature = "bincode-codec")]
+    Bincode,
+    #[cfg(feature = "msgpack-codec")]
+    MessagePack,
+    #[cfg(feature = "cbor-codec")]
+    Cbor,
+}
+
+impl SessionCodec {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, SessionCodecError> {
+        match self {
+            SessionCodec::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "bincode-codec")]
+            SessionCodec::Bincode => Ok(bincode::serialize(value)?),
+            #[cfg(feature = "msgpack-codec")]
+            SessionCodec::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+            #[cfg(feature = "cbor-codec")]
+            SessionCodec::Cbor => Ok(serde_cbor::to_vec(value)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, SessionCodecError> {
+        match self {
+            SessionCodec::Json => Ok(serde_json::from_slice(bytes)?),
+            #[cfg(feature = "bincode-codec")]
+            SessionCodec::Bincode => Ok(bincode::deserialize(bytes)?),
+            #[cfg(feature = "msgpack-codec")]
+            SessionCodec::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+            #[cfg(feature = "cbor-codec")]
+            SessionCodec::Cbor => Ok(serde_cbor::from_slice(bytes)?),
+        }
+    }
+
+    /// Encodes `value` prefixed with the tag of this codec.
+    pub(crate) fn encode_tagged<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, SessionCodecError> {
+        let mut bytes = match self.tag() {
+            Some(tag) => vec![tag],
+            None => Vec::new(),
+        };
+        bytes.extend(self.encode(value)?);
+        Ok(bytes)
+    }
+
+    /// Decodes bytes written by `encode_tagged`, returning the codec they were written with.
+    pub(crate) fn decode_tagged<T: DeserializeOwned>(bytes: &[u8]) -> Result<(SessionCodec, T), SessionCodecError> {
+        let (codec, body) = match bytes.first() {
+            #[cfg(feature = "bincode-codec")]
+            Some(1) => (SessionCodec::Bincode, &bytes[1..]),
+            #[cfg(feature = "msgpack-codec")]
+            Some(2) => (SessionCodec::MessagePack, &bytes[1..]),
+            #[cfg(feature = "cbor-codec")]
+            Some(3) => (SessionCodec::Cbor, &bytes[1..]),
+            Some(&tag) if (1..=8).contains(&tag) => return Err(SessionCodecError::UnknownCodec(tag)),
+            _ => (SessionCodec::Json, bytes),
+        };
+        Ok((codec, codec.decode(body)?))
+    }
+
+    fn tag(&self) -> Option<u8> {
+        match self {
+            SessionCodec::Json => None,
+            #[cfg(feature = "bincode-codec")]
+            SessionCodec::Bincode => Some(1),
+            #[cfg(feature = "msgpack-codec")]
+            SessionCodec::MessagePack => Some(2),
+            #[cfg(feature = "cbor-codec")]
+            SessionCodec::Cbor => Some(3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::server_session_state::State;
+
+    use super::*;
+
+    fn codecs() -> Vec<SessionCodec> {
+        #[allow(unused_mut)]
+        let mut codecs = vec![SessionCodec::Json];
+        #[cfg(feature = "bincode-codec")]
+        codecs.push(SessionCodec::Bincode);
+        #[cfg(feature = "msgpack-codec")]
+        codecs.push(SessionCodec::MessagePack);
+        #[cfg(feature = "cbor-codec")]
+        codecs.push(SessionCodec::Cbor);
+        codecs
+    }
+
+    #[test]
+    fn states_round_trip_with_their_codec() {
+        for codec in codecs() {
+            let mut state = State::with_codec(Duration::from_secs(60), codec);
+            state.set("user", &"alice").unwrap();
+            state.set("visits", &vec![1, 2, 3]).unwrap();
+            let decoded = State::from_bytes(&state.to_bytes().unwrap()).unwrap();
+            assert_eq!(decoded.codec(), codec);
+            assert_eq!(decoded.get::<String>("user").unwrap().as_deref(), Some("alice"));
+            assert_eq!(decoded.get::<Vec<i32>>("visits").unwrap(), Some(vec![1, 2, 3]));
+        }
+    }
+
+    #[test]
+    fn json_states_are_untagged() {
+        let bytes = State::new(Duration::from_secs(60)).to_bytes().unwrap();
+        assert_eq!(bytes[0], b'{');
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        match SessionCodec::decode_tagged::<State>(&[8, 0]) {
+            Err(SessionCodecError::UnknownCodec(8)) => {}
+            _ => panic!("tag 8 decoded"),
+        }
+    }
+}
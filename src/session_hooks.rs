@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use crate::server_session_state::State;
+
+/// Callback receiving the store id and the final state of a session.
+pub type SessionHook = Arc<dyn Fn(&str, &State) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct SessionHooks {
+    pub(crate) on_create: Vec<SessionHook>,
+    pub(crate) on_expire: Vec<SessionHook>,
+    pub(crate) on_purge: Vec<SessionHook>,
+    pub(crate) on_renew: Vec<SessionHook>,
+}
+
+pub(crate) fn call_hooks(hooks: &[SessionHook], id: &str, state: &State) {
+    for hook in hooks {
+        hook(id, state);
+    }
+}
@@ -0,0 +1,24 @@
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use derive_more::Display;
+
+/// What the middleware does when a principal signs in while it already has
+/// as many sessions as allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionLimitPolicy {
+    /// Refuse the new login with `SessionLimitError`, existing sessions are kept.
+    RejectNew,
+    /// Remove the oldest sessions of the principal to make room for the new one.
+    EvictOldest,
+}
+
+/// Error returned instead of the response of a login refused by `SessionLimitPolicy::RejectNew`
+#[derive(Debug, Display)]
+#[display(fmt = "Too many sessions for this principal")]
+pub struct SessionLimitError;
+
+impl ResponseError for SessionLimitError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::CONFLICT
+    }
+}
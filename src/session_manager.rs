@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use actix_web::Error;
+
+use crate::server_session_inner::ServerSessionInner;
+use crate::server_session_state::State;
+use crate::session_hooks::call_hooks;
+
+/// Handle revoking sessions outside of their requests, e.g. after a password
+/// change or a security incident.
+///
+/// Get it from a configured `ServerSession` and register it as app data:
+///
+/// ```rust,ignore
+/// let session = ServerSession::signed(&[0; 32]);
+/// App::new().data(session.manager()).wrap(session.clone())
+/// ```
+///
+/// Stores and hooks identify sessions by their store id, a keyed hash of the
+/// session id, see `store_id`. Revoked sessions are deleted from the store and
+/// passed to the `on_purge` hooks. Revoking by predicate or all sessions needs a store able to list
+/// its sessions.
+#[derive(Clone)]
+pub struct SessionManager(Arc<ServerSessionInner>);
+
+impl SessionManager {
+    pub(crate) fn new(inner: Arc<ServerSessionInner>) -> Self {
+        SessionManager(inner)
+    }
+
+    /// Returns the id session `id` is saved under in the store, the id hooks
+    /// and `revoke_where` receive.
+    pub fn store_id(&self, id: &str) -> String {
+        self.0.store_id(id)
+    }
+
+    /// Revokes session `id`, returning whether it existed.
+    ///
+    /// Sessions not used since a key rotation are found under their retired key.
+    pub async fn revoke(&self, id: &str) -> Result<bool, Error> {
+        let ids = std::iter::once(self.0.store_id(id)).chain(self.0.retired_store_ids(id)).collect();
+        Ok(self.revoke_matching(ids, |_, _| true).await? > 0)
+    }
+
+    /// Revokes every session of `principal`, see `Session::set_principal`.
+    ///
+    /// Returns how many sessions were revoked.
+    pub async fn revoke_principal(&self, principal: &str) -> Result<usize, Error> {
+        let ids = self.0.store.sessions_of(principal).await?;
+        self.revoke_matching(ids, |_, state| state.principal() == Some(principal)).await
+    }
+
+    /// Revokes every session for which `predicate`, given the store id and the
+    /// state of the session, returns true.
+    ///
+    /// Returns how many sessions were revoked.
+    pub async fn revoke_where<F>(&self, predicate: F) -> Result<usize, Error>
+        where
+            F: Fn(&str, &State) -> bool,
+    {
+        let ids = self.0.store.ids().await?;
+        self.revoke_matching(ids, predicate).await
+    }
+
+    /// Revokes every session, returning how many were revoked.
+    pub async fn revoke_all(&self) -> Result<usize, Error> {
+        self.revoke_where(|_, _| true).await
+    }
+
+    async fn revoke_matching<F>(&self, ids: Vec<String>, predicate: F) -> Result<usize, Error>
+        where
+            F: Fn(&str, &State) -> bool,
+    {
+        let store = &self.0.store;
+        let mut revoked = 0;
+        for id in ids {
+            match store.load(&id).await? {
+                Some(state) if predicate(&id, &state) => {
+                    store.delete(&id).await?;
+                    call_hooks(&self.0.hooks.on_purge, &id, &state);
+                    revoked += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(revoked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test, web};
+
+    use crate::server_session::ServerSession;
+    use crate::server_session::tests::{count, login, send};
+
+    #[actix_rt::test]
+    async fn revokes_by_session_id() {
+        let session = ServerSession::signed(&[0; 32]).id_generator(|| "id".to_owned());
+        let manager = session.manager();
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        assert!(manager.revoke("id").await.unwrap());
+        assert!(!manager.revoke("id").await.unwrap());
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "1");
+    }
+
+    #[actix_rt::test]
+    async fn revokes_by_principal_and_all() {
+        let session = ServerSession::signed(&[0; 32]);
+        let manager = session.manager();
+        let mut app = test::init_service(App::new().wrap(session)
+            .route("/", web::get().to(count))
+            .route("/login/{name}", web::get().to(login))).await;
+        let mut cookies = Vec::new();
+        for name in &["alice", "alice", "bob"] {
+            let req = test::TestRequest::get().uri(&format!("/login/{}", name)).to_request();
+            let res = test::call_service(&mut app, req).await;
+            cookies.push(res.response().cookies().next().unwrap().into_owned());
+        }
+        assert_eq!(send(&mut app, Some(&cookies[2])).await.1, "1");
+        assert_eq!(manager.revoke_principal("alice").await.unwrap(), 2);
+        // a revoked session is replaced by a new one
+        assert_eq!(send(&mut app, Some(&cookies[0])).await.1, "1");
+        assert_eq!(send(&mut app, Some(&cookies[2])).await.1, "2");
+        assert_eq!(manager.revoke_where(|_, state| state.principal() == Some("bob")).await.unwrap(), 1);
+        assert_eq!(manager.revoke_all().await.unwrap(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn revoked_sessions_reach_purge_hooks() {
+        let purged = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook = purged.clone();
+        let session = ServerSession::signed(&[0; 32])
+            .id_generator(|| "id".to_owned())
+            .on_purge(move |id, _| hook.lock().unwrap().push(id.to_owned()));
+        let manager = session.manager();
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        send(&mut app, None).await;
+        manager.revoke("id").await.unwrap();
+        assert_eq!(*purged.lock().unwrap(), vec![manager.store_id("id")]);
+    }
+}
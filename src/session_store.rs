@@ -0,0 +1,250 @@
+use std::io;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::{Error, ResponseError};
+use derive_more::{Display, From};
+use futures_util::future::{err, FutureExt, LocalBoxFuture, ok};
+
+use crate::server_session_state::State;
+use crate::session_codec::SessionCodecError;
+use crate::session_hooks::SessionHook;
+
+/// Errors that can occur while talking to a session store
+#[derive(Debug, From, Display)]
+pub enum SessionStoreError {
+    /// Fail to serialize or deserialize a session state.
+    #[display(fmt = "Fail to serialize session")]
+    Serialize(SessionCodecError),
+    /// I/O operation of the store failed.
+    #[display(fmt = "Session store I/O error: {}", _0)]
+    Io(io::Error),
+    /// Redis command failed.
+    #[cfg(feature = "redis-session")]
+    #[display(fmt = "Redis error: {}", _0)]
+    Redis(redis::RedisError),
+    /// SQLite statement failed.
+    #[cfg(feature = "sqlite-session")]
+    #[display(fmt = "SQLite error: {}", _0)]
+    Sqlite(rusqlite::Error),
+    /// The store lacks a feature, e.g. the principal index.
+    #[from(ignore)]
+    #[display(fmt = "Session store doesn't support {}", _0)]
+    Unsupported(&'static str),
+}
+
+impl ResponseError for SessionStoreError {}
+
+/// Maximum lifetime a store applies to the sessions it saves, see
+/// `SessionStore::set_max_lifetime`.
+#[derive(Default)]
+pub(crate) struct LifetimeCap(RwLock<Option<Duration>>);
+
+impl LifetimeCap {
+    pub(crate) fn get(&self) -> Option<Duration> {
+        *self.0.read().unwrap()
+    }
+
+    pub(crate) fn set(&self, max_lifetime: Option<Duration>) {
+        *self.0.write().unwrap() = max_lifetime;
+    }
+
+    /// Copy of `state` with the cap applied.
+    pub(crate) fn apply(&self, state: &State) -> State {
+        state.capped(self.get())
+    }
+}
+
+/// Backend holding the server side state of every session.
+///
+/// The middleware goes through this trait for every request, so a store
+/// must be shareable between actix workers. Operations return futures so
+/// that network backed stores don't block the worker while waiting on I/O.
+pub trait SessionStore: Send + Sync {
+    /// Load the state saved under `id`, if any.
+    fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>>;
+
+    /// Save `state` under `id`, replacing any previous state.
+    fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>>;
+
+    /// Mark the state saved under `id` as used now.
+    fn touch<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>>;
+
+    /// Remove the state saved under `id`.
+    fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>>;
+
+    /// Move the state saved under `old_id` to `new_id`, saving `state` there.
+    ///
+    /// The default implementation saves then deletes, stores able to do both
+    /// at once should override it.
+    fn rename<'a>(&'a self, old_id: &'a str, new_id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.save(new_id, state).await?;
+            self.delete(old_id).await
+        }.boxed_local()
+    }
+
+    /// Check whether a state is saved under `id`.
+    fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>>;
+
+    /// Ids of every saved session, expired ones included until they are removed.
+    ///
+    /// Stores unable to list their sessions return `SessionStoreError::Unsupported`.
+    fn ids(&self) -> LocalBoxFuture<'_, Result<Vec<String>, Error>> {
+        err(SessionStoreError::Unsupported("listing sessions").into()).boxed_local()
+    }
+
+    /// Ids of the sessions saved with `principal`, see `Session::set_principal`.
+    ///
+    /// Ids of expired sessions may be returned too. The default implementation
+    /// loads every session listed by `ids`, stores indexing principals should
+    /// override it.
+    fn sessions_of<'a>(&'a self, principal: &'a str) -> LocalBoxFuture<'a, Result<Vec<String>, Error>> {
+        async move {
+            let mut found = Vec::new();
+            for id in self.ids().await? {
+                if let Some(state) = self.load(&id).await? {
+                    if state.principal() == Some(principal) {
+                        found.push(id);
+                    }
+                }
+            }
+            Ok(found)
+        }.boxed_local()
+    }
+
+    /// Register `hook` to be called with every session the store removes
+    /// on its own, because it expired or to respect a capacity.
+    ///
+    /// Stores relying on a native expiry of their backend never call it.
+    fn on_expire(&self, hook: SessionHook) {
+        let _ = hook;
+    }
+
+    /// Caps how long every session may live since its creation, including
+    /// the sessions saved before. `ServerSession` passes its maximum lifetime.
+    ///
+    /// Stores relying on a native expiry of their backend apply it to the
+    /// sessions they write. The middleware refuses older sessions anyway.
+    fn set_max_lifetime(&self, max_lifetime: Option<Duration>) {
+        let _ = max_lifetime;
+    }
+
+    /// Stop the background work of the store and release its resources.
+    ///
+    /// Writes are complete once their future resolves, so there is nothing
+    /// left to flush when the middleware is no longer serving requests.
+    fn shutdown(&self) -> LocalBoxFuture<'_, Result<(), Error>> {
+        ok(()).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{App, test, web};
+    use futures_util::future::{FutureExt, ready};
+
+    use crate::server_session::ServerSession;
+    use crate::server_session::tests::{count, send};
+
+    use super::*;
+
+    /// Store implementing only the required operations.
+    #[derive(Default)]
+    struct MapStore(Mutex<HashMap<String, State>>);
+
+    impl SessionStore for MapStore {
+        fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>> {
+            ready(Ok(self.0.lock().unwrap().get(id).cloned())).boxed_local()
+        }
+
+        fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+            self.0.lock().unwrap().insert(id.to_owned(), state.clone());
+            ready(Ok(())).boxed_local()
+        }
+
+        fn touch<'a>(&'a self, _: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+            ready(Ok(())).boxed_local()
+        }
+
+        fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+            self.0.lock().unwrap().remove(id);
+            ready(Ok(())).boxed_local()
+        }
+
+        fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>> {
+            ready(Ok(self.0.lock().unwrap().contains_key(id))).boxed_local()
+        }
+    }
+
+    /// Store answering only after a delay, like a store on the network.
+    #[derive(Default)]
+    struct DelayedStore(MapStore);
+
+    impl DelayedStore {
+        async fn delay() {
+            actix_rt::time::delay_for(std::time::Duration::from_millis(5)).await;
+        }
+    }
+
+    impl SessionStore for DelayedStore {
+        fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>> {
+            DelayedStore::delay().then(move |_| self.0.load(id)).boxed_local()
+        }
+
+        fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+            DelayedStore::delay().then(move |_| self.0.save(id, state)).boxed_local()
+        }
+
+        fn touch<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+            DelayedStore::delay().then(move |_| self.0.touch(id)).boxed_local()
+        }
+
+        fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+            DelayedStore::delay().then(move |_| self.0.delete(id)).boxed_local()
+        }
+
+        fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>> {
+            DelayedStore::delay().then(move |_| self.0.exists(id)).boxed_local()
+        }
+    }
+
+    #[test]
+    fn default_operations() {
+        let store = MapStore::default();
+        store.save("old", &State::default()).now_or_never().unwrap().unwrap();
+        store.rename("old", "new", &State::default()).now_or_never().unwrap().unwrap();
+        assert!(!store.exists("old").now_or_never().unwrap().unwrap());
+        assert!(store.exists("new").now_or_never().unwrap().unwrap());
+        assert!(store.ids().now_or_never().unwrap().is_err());
+        assert!(store.sessions_of("alice").now_or_never().unwrap().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn middleware_uses_the_given_store() {
+        let store = Arc::new(MapStore::default());
+        let session = ServerSession::signed(&[0; 32]).store(store.clone());
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "2");
+        let states = store.0.lock().unwrap();
+        assert_eq!(states.len(), 1);
+        let state = states.values().next().unwrap();
+        assert_eq!(state.get::<i32>("count").unwrap(), Some(2));
+    }
+
+    #[actix_rt::test]
+    async fn middleware_awaits_the_store() {
+        let store = Arc::new(DelayedStore::default());
+        let session = ServerSession::signed(&[0; 32]).store(store.clone());
+        let mut app = test::init_service(App::new().wrap(session).route("/", web::get().to(count))).await;
+        let (cookie, _) = send(&mut app, None).await;
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "2");
+        assert_eq!(send(&mut app, cookie.as_ref()).await.1, "3");
+        let DelayedStore(MapStore(states)) = &*store;
+        assert_eq!(states.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,409 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::{Error, web};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::clock::{Clock, millis, SystemClock};
+use crate::expiry_worker::ExpiryWorker;
+use crate::server_session_state::State;
+use crate::session_hooks::{call_hooks, SessionHook};
+use crate::session_store::{LifetimeCap, SessionStore, SessionStoreError};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        payload BLOB NOT NULL,
+        last_use_time INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        principal TEXT
+    );
+    CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);
+";
+
+/// `payload`, `last_use_time`, `expires_at` and `principal` columns of a session.
+type Row = (Vec<u8>, i64, i64, Option<String>);
+
+struct SqliteSessionStoreInner {
+    conn: Mutex<Connection>,
+    sweep_interval: Duration,
+    expire_hooks: RwLock<Vec<SessionHook>>,
+    max_lifetime: LifetimeCap,
+    /// Set when the cap changed and saved rows must be capped by the next sweep.
+    recap: AtomicBool,
+    clock: Arc<dyn Clock>,
+    worker: Mutex<Option<ExpiryWorker>>,
+}
+
+impl SqliteSessionStoreInner {
+    fn read(conn: &Connection, id: &str) -> Result<Option<State>, SessionStoreError> {
+        let payload: Option<Vec<u8>> = conn
+            .query_row("SELECT payload FROM sessions WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()?;
+        match payload {
+            Some(payload) => Ok(Some(State::from_bytes(&payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Columns of the row holding `state`, with the lifetime cap of the store applied.
+    fn row(&self, state: &State) -> Result<Row, SessionStoreError> {
+        let state = self.max_lifetime.apply(state);
+        Ok((
+            state.to_bytes()?,
+            millis(state.last_use_time()),
+            millis(state.expires_at()),
+            state.principal().map(ToOwned::to_owned),
+        ))
+    }
+
+    fn write(conn: &Connection, id: &str, row: Row) -> Result<(), SessionStoreError> {
+        let (payload, last_use_time, expires_at, principal) = row;
+        conn.execute(
+            "INSERT OR REPLACE INTO sessions (id, payload, last_use_time, expires_at, principal)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, payload, last_use_time, expires_at, principal],
+        )?;
+        Ok(())
+    }
+
+    /// Rewrites the rows whose expiry the lifetime cap shortens.
+    fn cap_rows(&self) -> Result<(), SessionStoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let rows = tx
+            .prepare("SELECT id, payload, expires_at FROM sessions")?
+            .query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, i64>(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (id, payload, expires_at) in rows {
+            if let Ok(state) = State::from_bytes(&payload) {
+                let row = self.row(&state)?;
+                if row.2 < expires_at {
+                    SqliteSessionStoreInner::write(&tx, &id, row)?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn sweep(&self) -> Result<usize, SessionStoreError> {
+        if self.recap.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.cap_rows() {
+                self.recap.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        let now = millis(self.clock.now());
+        let hooks = self.expire_hooks.read().unwrap();
+        if hooks.is_empty() {
+            let removed = self.conn.lock().unwrap()
+                .execute("DELETE FROM sessions WHERE expires_at < ?1", params![now])?;
+            return Ok(removed);
+        }
+
+        // hooks need the expired states, read them in the deleting transaction
+        let expired = {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let expired = tx
+                .prepare("SELECT id, payload FROM sessions WHERE expires_at < ?1")?
+                .query_map(params![now], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            tx.execute("DELETE FROM sessions WHERE expires_at < ?1", params![now])?;
+            tx.commit()?;
+            expired
+        };
+        for (id, payload) in &expired {
+            if let Ok(state) = State::from_bytes(payload) {
+                call_hooks(&hooks, id, &state);
+            }
+        }
+        Ok(expired.len())
+    }
+}
+
+/// Session store keeping states in a SQLite database.
+///
+/// States live in a `sessions` table with the columns `id`, `payload`,
+/// `last_use_time`, `expires_at` and `principal`, times being milliseconds
+/// since the unix epoch. `expires_at` is indexed so `sweep` removes expired
+/// sessions with a single statement, `principal` to find the sessions of a
+/// principal.
+#[derive(Clone)]
+pub struct SqliteSessionStore(Arc<SqliteSessionStoreInner>);
+
+impl SqliteSessionStore {
+    /// Construct new `SqliteSessionStore` on the database file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteSessionStore, SessionStoreError> {
+        SqliteSessionStore::new(Connection::open(path)?)
+    }
+
+    /// Construct new `SqliteSessionStore` on an already opened connection.
+    ///
+    /// The `sessions` table is created if it doesn't exist, or given the
+    /// `principal` column if it lacks it.
+    pub fn new(conn: Connection) -> Result<SqliteSessionStore, SessionStoreError> {
+        conn.execute_batch(SCHEMA)?;
+        // tables created before the principal index lack its column
+        if conn.prepare("SELECT principal FROM sessions LIMIT 0").is_err() {
+            conn.execute_batch("ALTER TABLE sessions ADD COLUMN principal TEXT")?;
+        }
+        conn.execute_batch("CREATE INDEX IF NOT EXISTS sessions_principal ON sessions (principal)")?;
+        Ok(SqliteSessionStore(Arc::new(SqliteSessionStoreInner {
+            conn: Mutex::new(conn),
+            sweep_interval: Duration::from_secs(60),
+            expire_hooks: RwLock::new(Vec::new()),
+            max_lifetime: LifetimeCap::default(),
+            recap: AtomicBool::new(false),
+            clock: Arc::new(SystemClock),
+            worker: Mutex::new(None),
+        })))
+    }
+
+    /// Sets how often `start` sweeps expired sessions. Default is one minute.
+    pub fn sweep_interval(mut self, value: Duration) -> SqliteSessionStore {
+        Arc::get_mut(&mut self.0).unwrap().sweep_interval = value;
+        self
+    }
+
+    /// Sets the clock deciding when sessions expire. Default is `SystemClock`.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> SqliteSessionStore {
+        Arc::get_mut(&mut self.0).unwrap().clock = Arc::new(clock);
+        self
+    }
+
+    /// Deletes expired sessions, returning how many were deleted.
+    pub fn sweep(&self) -> Result<usize, SessionStoreError> {
+        self.0.sweep()
+    }
+
+    /// Starts the thread sweeping expired sessions periodically.
+    ///
+    /// It runs until `shutdown` is called or the store is dropped.
+    pub fn start(&self) {
+        ExpiryWorker::start(&self.0.worker, self.0.sweep_interval, Arc::downgrade(&self.0), |inner| {
+            let _ = inner.sweep();
+        });
+    }
+}
+
+impl SessionStore for SqliteSessionStore {
+    fn load<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<Option<State>, Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            Ok(web::block(move || {
+                SqliteSessionStoreInner::read(&inner.conn.lock().unwrap(), &id)
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            let row = inner.row(state)?;
+            web::block(move || {
+                SqliteSessionStoreInner::write(&inner.conn.lock().unwrap(), &id, row)
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn touch<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            web::block(move || {
+                let conn = inner.conn.lock().unwrap();
+                if let Some(mut state) = SqliteSessionStoreInner::read(&conn, &id)? {
+                    state.update_last_use_time(inner.clock.now());
+                    SqliteSessionStoreInner::write(&conn, &id, inner.row(&state)?)?;
+                }
+                Ok::<_, SessionStoreError>(())
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            web::block(move || {
+                inner.conn.lock().unwrap()
+                    .execute("DELETE FROM sessions WHERE id = ?1", params![id])
+                    .map_err(SessionStoreError::from)
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn rename<'a>(&'a self, old_id: &'a str, new_id: &'a str, state: &'a State) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let inner = self.0.clone();
+        let (old_id, new_id) = (old_id.to_owned(), new_id.to_owned());
+        async move {
+            let row = inner.row(state)?;
+            web::block(move || {
+                let mut conn = inner.conn.lock().unwrap();
+                let tx = conn.transaction()?;
+                tx.execute("DELETE FROM sessions WHERE id = ?1", params![old_id])?;
+                SqliteSessionStoreInner::write(&tx, &new_id, row)?;
+                tx.commit()?;
+                Ok::<_, SessionStoreError>(())
+            }).await?;
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn ids(&self) -> LocalBoxFuture<'_, Result<Vec<String>, Error>> {
+        let inner = self.0.clone();
+        async move {
+            Ok(web::block(move || {
+                inner.conn.lock().unwrap()
+                    .prepare("SELECT id FROM sessions")?
+                    .query_map(params![], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()
+                    .map_err(SessionStoreError::from)
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn sessions_of<'a>(&'a self, principal: &'a str) -> LocalBoxFuture<'a, Result<Vec<String>, Error>> {
+        let inner = self.0.clone();
+        let principal = principal.to_owned();
+        async move {
+            Ok(web::block(move || {
+                let now = millis(inner.clock.now());
+                inner.conn.lock().unwrap()
+                    .prepare("SELECT id FROM sessions WHERE principal = ?1 AND expires_at >= ?2")?
+                    .query_map(params![principal, now], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()
+                    .map_err(SessionStoreError::from)
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn exists<'a>(&'a self, id: &'a str) -> LocalBoxFuture<'a, Result<bool, Error>> {
+        let inner = self.0.clone();
+        let id = id.to_owned();
+        async move {
+            Ok(web::block(move || {
+                inner.conn.lock().unwrap()
+                    .query_row("SELECT 1 FROM sessions WHERE id = ?1", params![id], |_| Ok(()))
+                    .optional()
+                    .map(|row| row.is_some())
+                    .map_err(SessionStoreError::from)
+            }).await?)
+        }.boxed_local()
+    }
+
+    fn on_expire(&self, hook: SessionHook) {
+        self.0.expire_hooks.write().unwrap().push(hook);
+    }
+
+    /// Rows saved before are capped by the next sweep.
+    fn set_max_lifetime(&self, max_lifetime: Option<Duration>) {
+        self.0.max_lifetime.set(max_lifetime);
+        self.0.recap.store(max_lifetime.is_some(), Ordering::SeqCst);
+    }
+
+    fn shutdown(&self) -> LocalBoxFuture<'_, Result<(), Error>> {
+        ExpiryWorker::shutdown(&self.0.worker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::clock::MockClock;
+    use crate::server_session_state::tests::{MINUTE, state_at};
+
+    use super::*;
+
+    fn store(clock: &MockClock) -> SqliteSessionStore {
+        SqliteSessionStore::open(":memory:").unwrap().clock(clock.clone())
+    }
+
+    #[actix_rt::test]
+    async fn saves_touches_and_deletes() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let store = store(&clock);
+        assert!(store.load("a").await.unwrap().is_none());
+        let mut state = state_at(&clock, MINUTE);
+        state.set("user", &"alice").unwrap();
+        store.save("a", &state).await.unwrap();
+        clock.advance(MINUTE / 2);
+        store.touch("a").await.unwrap();
+        let loaded = store.load("a").await.unwrap().unwrap();
+        assert_eq!(loaded.get::<String>("user").unwrap().as_deref(), Some("alice"));
+        assert_eq!(loaded.last_use_time(), clock.now());
+        store.rename("a", "b", &loaded).await.unwrap();
+        assert!(!store.exists("a").await.unwrap());
+        assert_eq!(store.ids().await.unwrap(), vec!["b"]);
+        store.delete("b").await.unwrap();
+        assert!(store.ids().await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn sweep_passes_expired_states_to_hooks() {
+        let clock = MockClock::default();
+        let store = store(&clock);
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let hook = expired.clone();
+        store.on_expire(Arc::new(move |id: &str, _: &State| hook.lock().unwrap().push(id.to_owned())));
+        store.save("short", &state_at(&clock, MINUTE)).await.unwrap();
+        store.save("long", &state_at(&clock, 10 * MINUTE)).await.unwrap();
+        clock.advance(2 * MINUTE);
+        assert_eq!(store.sweep().unwrap(), 1);
+        assert_eq!(*expired.lock().unwrap(), vec!["short"]);
+        assert_eq!(store.ids().await.unwrap(), vec!["long"]);
+    }
+
+    #[test]
+    fn adds_the_principal_column_to_old_tables() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE sessions (
+            id TEXT PRIMARY KEY NOT NULL,
+            payload BLOB NOT NULL,
+            last_use_time INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL
+        )").unwrap();
+        let store = SqliteSessionStore::new(conn).unwrap();
+        assert!(store.0.conn.lock().unwrap().prepare("SELECT principal FROM sessions").is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn max_lifetime_caps_saved_rows() {
+        let clock = MockClock::default();
+        let store = store(&clock);
+        store.save("old", &state_at(&clock, 60 * MINUTE)).await.unwrap();
+        store.set_max_lifetime(Some(20 * MINUTE));
+        store.save("new", &state_at(&clock, 60 * MINUTE)).await.unwrap();
+        clock.advance(21 * MINUTE);
+        assert_eq!(store.sweep().unwrap(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_of_lists_live_sessions_of_the_principal() {
+        let clock = MockClock::default();
+        let store = store(&clock);
+        for (id, principal, timeout) in [("a", "alice", MINUTE), ("b", "alice", 10 * MINUTE), ("c", "bob", MINUTE)] {
+            let mut state = state_at(&clock, timeout);
+            state.set_principal(principal.to_owned());
+            store.save(id, &state).await.unwrap();
+        }
+        let mut ids = store.sessions_of("alice").await.unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b"]);
+        clock.advance(2 * MINUTE);
+        assert_eq!(store.sessions_of("alice").await.unwrap(), vec!["b"]);
+        store.delete("b").await.unwrap();
+        assert!(store.sessions_of("alice").await.unwrap().is_empty());
+    }
+}